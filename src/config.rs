@@ -0,0 +1,155 @@
+// Defaults read from `$env.config.plugins.socket`.
+
+use nu_plugin::EngineInterface;
+use nu_protocol::{LabeledError, Spanned, Value};
+
+/// Settings from the plugin's config record. Command-line flags take
+/// precedence over all of them.
+#[derive(Default)]
+pub struct Config {
+    /// Default for --timeout, in nanoseconds.
+    pub timeout: Option<i64>,
+    /// Default for --read-timeout of `socket connect`, in nanoseconds.
+    pub read_timeout: Option<i64>,
+    /// Default for --bind of `socket connect`.
+    pub bind: Option<Spanned<String>>,
+    /// Use TLS for TCP connections of `socket connect`.
+    pub tls: bool,
+}
+
+impl Config {
+    pub fn load(
+        engine: &EngineInterface,
+    ) -> Result<Self, LabeledError> {
+        Config::from_value(engine.get_plugin_config()?)
+    }
+
+    /// Reads the settings from the config record, if there is one.
+    fn from_value(value: Option<Value>) -> Result<Self, LabeledError> {
+        let Some(value) = value else {
+            return Ok(Config::default());
+        };
+        let record = value
+            .as_record()
+            .map_err(|e| invalid("socket", &value, e.to_string()))?;
+
+        let mut config = Config::default();
+        if let Some(timeout) = record.get("timeout") {
+            config.timeout = Some(duration("timeout", timeout)?);
+        }
+        if let Some(read_timeout) = record.get("read_timeout") {
+            config.read_timeout =
+                Some(duration("read_timeout", read_timeout)?);
+        }
+        if let Some(bind) = record.get("bind") {
+            config.bind = Some(Spanned {
+                item: bind
+                    .as_str()
+                    .map_err(|e| invalid("bind", bind, e.to_string()))?
+                    .to_string(),
+                span: bind.span(),
+            });
+        }
+        if let Some(tls) = record.get("tls") {
+            config.tls = tls
+                .as_bool()
+                .map_err(|e| invalid("tls", tls, e.to_string()))?;
+        }
+        Ok(config)
+    }
+}
+
+/// A timeout setting in nanoseconds, which has to be above zero.
+fn duration(setting: &str, value: &Value) -> Result<i64, LabeledError> {
+    let nanos = value
+        .as_duration()
+        .map_err(|e| invalid(setting, value, e.to_string()))?;
+    if nanos <= 0 {
+        return Err(invalid(
+            setting,
+            value,
+            "The timeout must be positive.",
+        ));
+    }
+    Ok(nanos)
+}
+
+fn invalid(
+    setting: &str,
+    value: &Value,
+    help: impl Into<String>,
+) -> LabeledError {
+    LabeledError::new(format!(
+        "Invalid `{}` in the socket plugin configuration",
+        setting
+    ))
+    .with_help(help)
+    .with_label("set here", value.span())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nu_protocol::{record, Record, Span};
+
+    fn settings(record: Record) -> Option<Value> {
+        Some(Value::record(record, Span::test_data()))
+    }
+
+    fn rejected(value: Option<Value>) -> String {
+        Config::from_value(value)
+            .err()
+            .expect("the setting should be rejected")
+            .msg
+    }
+
+    #[test]
+    fn defaults_without_a_config() {
+        for value in [None, settings(record! {})] {
+            let config = Config::from_value(value).unwrap();
+            assert_eq!(config.timeout, None);
+            assert_eq!(config.read_timeout, None);
+            assert!(config.bind.is_none());
+            assert!(!config.tls);
+        }
+    }
+
+    #[test]
+    fn settings_override_the_defaults() {
+        let span = Span::test_data();
+        let config = Config::from_value(settings(record! {
+            "timeout" => Value::duration(3_000_000_000, span),
+            "read_timeout" => Value::duration(500_000_000, span),
+            "bind" => Value::string("127.0.0.1", span),
+            "tls" => Value::bool(true, span),
+        }))
+        .unwrap();
+        assert_eq!(config.timeout, Some(3_000_000_000));
+        assert_eq!(config.read_timeout, Some(500_000_000));
+        assert_eq!(config.bind.unwrap().item, "127.0.0.1");
+        assert!(config.tls);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let span = Span::test_data();
+        assert!(rejected(Some(Value::string("10sec", span)))
+            .contains("`socket`"));
+        assert!(rejected(settings(record! {
+            "timeout" => Value::string("10sec", span),
+        }))
+        .contains("`timeout`"));
+        assert!(rejected(settings(record! {
+            "read_timeout" => Value::duration(-1_000_000_000, span),
+        }))
+        .contains("`read_timeout`"));
+        assert!(rejected(settings(record! {
+            "timeout" => Value::duration(0, span),
+        }))
+        .contains("`timeout`"));
+        assert!(rejected(settings(record! {
+            "tls" => Value::string("yes", span),
+        }))
+        .contains("`tls`"));
+    }
+}
@@ -1,13 +1,40 @@
 use super::SocketPlugin;
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::encoding::InputEncoding;
+use crate::flags::positive_duration;
+use crate::framing;
+use crate::json;
+use crate::pool::{Checkout, Connection, PoolKey, TlsKey};
+use crate::proxy::{Destination, Proxy};
+use crate::proxy_protocol;
+use crate::resolve::{
+    host_port, resolve, split_port, unbracket, Family,
+};
+use crate::starttls::Starttls;
+use crate::throttle::Throttled;
+use crate::tls;
 use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
 use nu_protocol::{
-    ByteStream, ByteStreamSource, ByteStreamType, Category, DataSource,
-    Example, LabeledError, PipelineData, PipelineMetadata, Record,
-    Signature, SyntaxShape, Value,
+    record, ByteStream, ByteStreamSource, ByteStreamType, Category,
+    DataSource, Example, LabeledError, ListStream, PipelineData,
+    PipelineMetadata, Record, Signals, Signature, Span, Spanned,
+    SyntaxShape, Value,
+};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream,
+    ToSocketAddrs, UdpSocket,
 };
-use std::io::Write;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
-use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct Connect;
 
@@ -29,14 +56,354 @@ impl PluginCommand for Connect {
                 SyntaxShape::String,
                 "The hostname or IP address to connect to.",
             )
-            .required("port", SyntaxShape::Int, "The port number to connect to.")
+            .optional(
+                "port",
+                SyntaxShape::Int,
+                "The port number to connect to, unless host is given as host:port. Not used with --unix.",
+            )
             .named(
                 "timeout",
                 SyntaxShape::Duration,
-                "Timeout for network operations. Defaults to 10 seconds.",
+                "Timeout for connecting. Also used for reads unless --read-timeout is given. Defaults to 10 seconds.",
                 Some('t'),
             )
+            .named(
+                "read-timeout",
+                SyntaxShape::Duration,
+                "Timeout for waiting on data from the remote host. Defaults to --timeout.",
+                None,
+            )
+            .named(
+                "retries",
+                SyntaxShape::Int,
+                "Retry a failed TCP connection this many times. Defaults to 0.",
+                None,
+            )
+            .named(
+                "retry-delay",
+                SyntaxShape::Duration,
+                "How long to wait between connection attempts with --retries. Defaults to 1 second.",
+                None,
+            )
             .switch("udp", "Use UDP protocol instead of TCP.", Some('u'))
+            .switch(
+                "collect",
+                "With --udp, keep receiving datagrams until the read timeout elapses and return them concatenated.",
+                None,
+            )
+            .switch(
+                "structured",
+                "With --collect, return a list of {from, data} records, one per datagram, instead of concatenating them.",
+                None,
+            )
+            .named(
+                "count",
+                SyntaxShape::Int,
+                "With --collect, stop as soon as this many datagrams have arrived instead of waiting out the read timeout.",
+                None,
+            )
+            .switch(
+                "with-source",
+                "With --udp, return the reply as a {from_addr, from_port, data} record, to see which address and port it came from.",
+                None,
+            )
+            .switch(
+                "from-json",
+                "With --udp, parse the reply as JSON. A reply that is not valid JSON is returned as binary with a warning.",
+                None,
+            )
+            .switch(
+                "broadcast",
+                "With --udp, allow sending to a broadcast address.",
+                None,
+            )
+            .named(
+                "multicast",
+                SyntaxShape::String,
+                "With --udp, join this multicast group on the interface given by --bind.",
+                None,
+            )
+            .switch(
+                "unix",
+                "Treat host as the path of a Unix domain socket to connect to.",
+                None,
+            )
+            .switch(
+                "no-delay",
+                "Disable Nagle's algorithm (TCP_NODELAY) so small writes are sent immediately. TCP only.",
+                None,
+            )
+            .named(
+                "keepalive",
+                SyntaxShape::Duration,
+                "Enable TCP keepalive, probing the connection after it has been idle this long. TCP only.",
+                None,
+            )
+            .named(
+                "keepalive-interval",
+                SyntaxShape::Duration,
+                "With --keepalive, the time between probes once the connection is idle. Whole seconds.",
+                None,
+            )
+            .named(
+                "keepalive-retries",
+                SyntaxShape::Int,
+                "With --keepalive, how many unanswered probes mark the peer as dead.",
+                None,
+            )
+            .named(
+                "deadline",
+                SyntaxShape::Duration,
+                "Give up once the whole exchange has taken this long since connecting, however steadily data arrives. Not for UDP.",
+                None,
+            )
+            .named(
+                "max-rate",
+                SyntaxShape::Filesize,
+                "Limit sending and receiving to this many bytes per second each. Not for UDP.",
+                None,
+            )
+            .named(
+                "max-size",
+                SyntaxShape::Filesize,
+                "Fail once the reply grows past this size.",
+                None,
+            )
+            .switch(
+                "truncate",
+                "With --max-size, end the reply at the limit with a warning instead of failing.",
+                None,
+            )
+            .named(
+                "read-until",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::Binary,
+                    SyntaxShape::String,
+                ]),
+                "End the reply after this delimiter instead of waiting for the server to close the connection. Not for UDP.",
+                None,
+            )
+            .switch(
+                "auto-json",
+                "Send a record, list or table input as a line of JSON. Strings and binary are sent as they are.",
+                None,
+            )
+            .named(
+                "input-encoding",
+                SyntaxShape::String,
+                "Decode string input from hex or base64 before sending it, for bytes that are awkward to type.",
+                None,
+            )
+            .switch(
+                "hex-output",
+                "Return the reply as a hex dump, with offsets, hex columns and printable characters, like xxd.",
+                None,
+            )
+            .switch(
+                "first-line",
+                "Return only the first line of the reply, as a string, and close the connection. Not for UDP.",
+                None,
+            )
+            .named(
+                "length-prefixed",
+                SyntaxShape::Int,
+                "Send the input and read the reply as one message framed by a big-endian length header of this many bytes: 1, 2, 4 or 8.",
+                None,
+            )
+            .switch(
+                "output-split-frames",
+                "With --length-prefixed, keep reading frames until the connection closes or goes quiet, and return their payloads as a list.",
+                None,
+            )
+            .switch(
+                "strict",
+                "With --output-split-frames, fail on an incomplete last frame instead of dropping it with a warning.",
+                None,
+            )
+            .named(
+                "content-type",
+                SyntaxShape::String,
+                "Declare the reply's content type in its metadata, e.g. application/json.",
+                None,
+            )
+            .switch(
+                "crlf",
+                "Send line endings in string input as \\r\\n, as text protocols like SMTP and HTTP expect.",
+                None,
+            )
+            .named(
+                "send-file",
+                SyntaxShape::Filepath,
+                "Send the contents of this file, read as it is sent, instead of the pipeline input.",
+                None,
+            )
+            .named(
+                "output-file",
+                SyntaxShape::Filepath,
+                "Write the reply to this file as it arrives and return a summary instead of the reply. Not for UDP.",
+                None,
+            )
+            .named(
+                "line-delay",
+                SyntaxShape::Duration,
+                "Send the input a line at a time, pausing this long between lines. Not for UDP or --length-prefixed.",
+                None,
+            )
+            .named(
+                "repeat",
+                SyntaxShape::Int,
+                "Send the input this many times over the one connection. With --read-until or --length-prefixed, read a reply after each send and return the replies as a list.",
+                None,
+            )
+            .named(
+                "repeat-delay",
+                SyntaxShape::Duration,
+                "With --repeat, pause this long before each send after the first.",
+                None,
+            )
+            .switch(
+                "shutdown-write",
+                "Close the sending half of the connection after the input is sent, so the remote host sees end-of-file before it replies.",
+                None,
+            )
+            .switch(
+                "proxy-protocol",
+                "Start the connection with a PROXY protocol v1 header giving its source and destination, for servers behind a PROXY-aware load balancer. TCP only.",
+                None,
+            )
+            .switch(
+                "interactive",
+                "Send the input as it arrives while streaming the reply back at the same time, like nc, instead of sending all of it first. TCP and --unix only.",
+                Some('i'),
+            )
+            .switch(
+                "reuse",
+                "Keep the connection open after the reply and use it again for the next call to the same destination. Needs --read-until, --length-prefixed or --first-line to tell where a reply ends.",
+                None,
+            )
+            .switch(
+                "tls",
+                "Encrypt the connection with TLS, verifying the server against the system's trusted certificates.",
+                None,
+            )
+            .switch(
+                "insecure",
+                "With --tls, accept any server certificate. For testing only.",
+                Some('k'),
+            )
+            .named(
+                "starttls",
+                SyntaxShape::String,
+                "Start in plaintext and switch to TLS once the server agrees: smtp, imap or custom. Takes the same TLS flags as --tls.",
+                None,
+            )
+            .named(
+                "starttls-command",
+                SyntaxShape::String,
+                "With --starttls custom, the command that asks for TLS, including its line ending.",
+                None,
+            )
+            .named(
+                "starttls-expect",
+                SyntaxShape::String,
+                "With --starttls custom, how the server's reply to --starttls-command must start.",
+                None,
+            )
+            .switch(
+                "starttls-greeting",
+                "With --starttls custom, read the server's greeting line before sending --starttls-command.",
+                None,
+            )
+            .named(
+                "tls-sni",
+                SyntaxShape::String,
+                "Server name to send and verify with --tls, if it differs from host.",
+                None,
+            )
+            .named(
+                "tls-alpn",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "Application protocols to offer with --tls via ALPN, e.g. [h2 http/1.1]. The one the server picks is in the reply's metadata.",
+                None,
+            )
+            .switch(
+                "tls-session-cache",
+                "With --tls, keep the TLS session and resume it on later connections to the same server, for a shorter handshake.",
+                None,
+            )
+            .switch(
+                "early-data",
+                "With --tls-session-cache, send the input as TLS 1.3 early data (0-RTT) when a session is resumed. Only for requests that are safe to replay.",
+                None,
+            )
+            .named(
+                "client-cert",
+                SyntaxShape::Filepath,
+                "PEM file with a client certificate chain to present with --tls, for servers that require mutual TLS. Requires --client-key.",
+                None,
+            )
+            .named(
+                "client-key",
+                SyntaxShape::Filepath,
+                "PEM file with the private key for --client-cert.",
+                None,
+            )
+            .switch(
+                "no-resolve",
+                "Require host to be an IP address, so no DNS lookup is ever made. The same goes for the --proxy host.",
+                None,
+            )
+            .switch(
+                "ipv4",
+                "Only connect to an IPv4 address of host.",
+                Some('4'),
+            )
+            .switch(
+                "ipv6",
+                "Only connect to an IPv6 address of host.",
+                Some('6'),
+            )
+            .named(
+                "recv-buffer",
+                SyntaxShape::Filesize,
+                "Size of the socket's receive buffer (SO_RCVBUF). The system may adjust it.",
+                None,
+            )
+            .named(
+                "send-buffer",
+                SyntaxShape::Filesize,
+                "Size of the socket's send buffer (SO_SNDBUF). The system may adjust it.",
+                None,
+            )
+            .named(
+                "ttl",
+                SyntaxShape::Int,
+                "IP time-to-live (hop limit for IPv6) for outgoing packets.",
+                None,
+            )
+            .named(
+                "bind",
+                SyntaxShape::String,
+                "Local address to send from, e.g. 192.168.1.5 or 192.168.1.5:0.",
+                Some('b'),
+            )
+            .named(
+                "local-port",
+                SyntaxShape::Int,
+                "Send from this local port instead of one the system picks, on the --bind address if given.",
+                None,
+            )
+            .switch(
+                "verbose",
+                "Print the resolved addresses, the one connected to and how long each step took to stderr.",
+                Some('v'),
+            )
+            .named(
+                "proxy",
+                SyntaxShape::String,
+                "Connect through a SOCKS5 or HTTP proxy, e.g. socks5://127.0.0.1:1080 or http://proxy:3128. Use socks5h:// to let a SOCKS proxy resolve host, and user:pass@ for credentials.",
+                None,
+            )
             .category(Category::Network)
     }
 
@@ -47,149 +414,3837 @@ impl PluginCommand for Connect {
                 description: "Connect to a web server on port 80 using TCP.",
                 result: None,
             },
+            Example {
+                example: r#""HEAD / HTTP/1.0\nHost: example.com\n\n" | socket connect example.com 80 --crlf | decode"#,
+                description: "Write the request with plain newlines and let --crlf send them as \\r\\n.",
+                result: None,
+            },
+            Example {
+                example: r#""PING\r\n" | socket connect 127.0.0.1 6379 --read-until "\r\n" | decode"#,
+                description: "Read a single-line reply from a server that keeps the connection open.",
+                result: None,
+            },
+            Example {
+                example: r#""PING\r\n" | socket connect localhost:6379 --read-until "\r\n""#,
+                description: "Give the port together with the host, as nc and curl accept it.",
+                result: None,
+            },
+            Example {
+                example: "socket connect smtp.example.com 25 --first-line",
+                description: "Read a server's greeting banner.",
+                result: None,
+            },
+            Example {
+                example: r#""GET / HTTP/1.0\r\nHost: example.com\r\n\r\n" | socket connect example.com 80 --proxy socks5h://127.0.0.1:9050 | decode"#,
+                description: "Fetch a page through a local Tor SOCKS proxy, which also resolves the hostname.",
+                result: None,
+            },
+            Example {
+                example: r#"0x[01 02 03] | socket connect 127.0.0.1 7001 --length-prefixed 4"#,
+                description: "Send a 4-byte length-prefixed message and return the payload of the framed reply.",
+                result: None,
+            },
+            Example {
+                example: r#""subscribe" | socket connect 127.0.0.1 7001 --length-prefixed 4 --output-split-frames | each { decode | from json }"#,
+                description: "Read every length-prefixed frame the server sends until it closes the connection.",
+                result: None,
+            },
             Example {
                 example: r#""il\r\n" | socket connect whois.iana.org 43"#,
                 description: "This command queries a WHOIS server for information about the `.il` domain.",
                 result: None,
             },
+            Example {
+                example: r#""GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n" | socket connect example.com 443 --tls | decode"#,
+                description: "Fetch a page over HTTPS.",
+                result: None,
+            },
+            Example {
+                example: r#""GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n" | socket connect localhost 8443 --tls --insecure | decode"#,
+                description: "Talk to a local server with a self-signed certificate. --insecure disables certificate checks, so use it for testing only.",
+                result: None,
+            },
+            Example {
+                example: r#""GET /status HTTP/1.0\r\n\r\n" | socket connect api.internal 443 --tls --client-cert client.pem --client-key client.key | decode"#,
+                description: "Call a service that requires mutual TLS.",
+                result: None,
+            },
+            Example {
+                example: r#""EHLO me\r\nQUIT\r\n" | socket connect smtp.example.com 587 --starttls smtp | decode"#,
+                description: "Greet a mail server over TLS after upgrading the plaintext connection with STARTTLS.",
+                result: None,
+            },
+            Example {
+                example: r#"{method: "status", id: 1} | socket connect localhost 9000 --auto-json --first-line | from json"#,
+                description: "Talk to a JSON-lines service.",
+                result: None,
+            },
+            Example {
+                example: r#""deadbeef" | socket connect localhost 9000 --input-encoding hex"#,
+                description: "Send the four bytes 0xde 0xad 0xbe 0xef.",
+                result: None,
+            },
+            Example {
+                example: r#""HEAD / HTTP/1.0\r\n\r\n" | socket connect example.com 80 --hex-output"#,
+                description: "Show the reply byte by byte, to spot stray line endings or control characters.",
+                result: None,
+            },
+            Example {
+                example: r#"tail -f commands.txt | socket connect localhost 9000 --interactive --read-timeout 1hr"#,
+                description: "Feed a server commands as they are written to a file, seeing its answers as they come.",
+                result: None,
+            },
+            Example {
+                example: r#"1..100 | each { "PING\n" | socket connect localhost 9000 --reuse --first-line }"#,
+                description: "Send many requests over one connection instead of opening a new one each time.",
+                result: None,
+            },
+            Example {
+                example: r#""M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: ssdp:all\r\n\r\n" | socket connect 239.255.255.250 1900 --udp --collect --read-timeout 3sec | decode"#,
+                description: "Discover UPnP devices, gathering every reply that arrives within three seconds.",
+                result: None,
+            },
+            Example {
+                example: r#""DISCOVER" | socket connect 255.255.255.255 1900 --udp --broadcast --collect --structured | get from"#,
+                description: "List the addresses of every host that answered a broadcast.",
+                result: None,
+            },
+            Example {
+                example: r#""status" | socket connect 127.0.0.1 27015 --udp --from-json | get players"#,
+                description: "Query a UDP service that answers in JSON and use the reply as a record.",
+                result: None,
+            },
+            Example {
+                example: r#""DISCOVER" | socket connect 255.255.255.255 1900 --udp --broadcast --collect"#,
+                description: "Broadcast a datagram on the local network and collect the answers.",
+                result: None,
+            },
+            Example {
+                example: r#""M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: ssdp:all\r\n\r\n" | socket connect 239.255.255.250 1900 --udp --multicast 239.255.255.250 --bind 192.168.1.5 --collect"#,
+                description: "Join the SSDP multicast group on a specific interface and gather the replies.",
+                result: None,
+            },
+            Example {
+                example: r#""PING\r\n" | socket connect 127.0.0.1 6379 --no-delay | decode"#,
+                description: "Send a small command to a Redis server without Nagle's algorithm delaying it.",
+                result: None,
+            },
+            Example {
+                example: r#""GET / HTTP/1.0\r\nHost: example.com\r\n\r\n" | socket connect lb.example.com 80 --proxy-protocol | decode"#,
+                description: "Talk to a server that sits behind a PROXY protocol load balancer.",
+                result: None,
+            },
+            Example {
+                example: r#""GET /version HTTP/1.0\r\n\r\n" | socket connect --unix /var/run/docker.sock | decode"#,
+                description: "Query the Docker daemon over its Unix domain socket.",
+                result: None,
+            },
+            Example {
+                example: r#""PING\r\n" | socket connect 127.0.0.1 6379 --repeat 10 --repeat-delay 100ms --read-until "\r\n" | each { decode }"#,
+                description: "Send the same command ten times over one connection and get each reply.",
+                result: None,
+            },
+            Example {
+                example: r#"["PING\r\n", "INCR visits\r\n"] | socket connect 127.0.0.1 6379 --read-until "\r\n" | each { decode }"#,
+                description: "Send each item of a list as its own message on one connection and get the reply to each.",
+                result: None,
+            },
+            Example {
+                example: "open --raw big.iso | socket connect 192.168.1.10 9000",
+                description: "Stream a file to a TCP server without loading it into memory.",
+                result: None,
+            },
         ]
     }
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        self.connect(plugin, engine, call, input, true)
+    }
+}
+
+impl Connect {
+    /// Runs the command. With `split_lists`, each item of a list input is
+    /// sent as a message of its own; `socket request` passes `false` to
+    /// keep a list input an error.
+    pub fn connect(
+        &self,
+        plugin: &SocketPlugin,
         engine: &EngineInterface,
         call: &EvaluatedCall,
         input: PipelineData,
+        split_lists: bool,
     ) -> Result<PipelineData, LabeledError> {
         let head = call.head;
         let host: String = call.req(0)?;
-        let port_val: i64 = call.req(1)?;
-        let port: u16 = port_val.try_into().map_err(|e| {
-            LabeledError::new("Invalid port number")
-                .with_help(format!(
-                    "Port must be between 0 and 65535. Error: {}",
-                    e
-                ))
-                .with_label("here", call.positional[1].span())
-        })?;
+        let config = Config::load(engine)?;
 
         let use_udp = call.has_flag("udp")?;
-
-        let timeout_val: Option<i64> = call.get_flag("timeout")?;
-        let timeout = Duration::from_nanos(
-            timeout_val.unwrap_or(10_000_000_000) as u64,
-        );
-
-        let input_val = input.into_value(head)?;
-        let input_bytes = match &input_val {
-            Value::String { val, .. } => val.as_bytes().to_vec(),
-            Value::Binary { val, .. } => val.clone(),
-            Value::Nothing { .. } => vec![],
-            other => {
-                return Err(LabeledError::new("Unsupported input type")
-                    .with_help(format!(
-                        "Expected string or binary, but got {}",
-                        other.get_type()
-                    ))
-                    .with_label("input originates from here", head))
+        let verbose = call.has_flag("verbose")?;
+        let use_unix = call.has_flag("unix")?;
+        let no_delay = call.has_flag("no-delay")?;
+        let shutdown_write = call.has_flag("shutdown-write")?;
+        let keepalive = positive_duration(call, "keepalive")?;
+        let keepalive_interval: Option<Spanned<i64>> =
+            call.get_flag("keepalive-interval")?;
+        let keepalive_retries: Option<Spanned<i64>> =
+            call.get_flag("keepalive-retries")?;
+        if keepalive.is_none() {
+            require_flags(
+                "keepalive",
+                &[
+                    (
+                        "keepalive-interval",
+                        keepalive_interval.is_some(),
+                    ),
+                    ("keepalive-retries", keepalive_retries.is_some()),
+                ],
+                head,
+            )?;
+        }
+        let starttls =
+            match call.get_flag::<Spanned<String>>("starttls")? {
+                Some(protocol) => Some(Starttls::parse(
+                    &protocol,
+                    call.get_flag("starttls-command")?,
+                    call.get_flag("starttls-expect")?,
+                    call.has_flag("starttls-greeting")?,
+                )?),
+                None => {
+                    require_flags(
+                        "starttls",
+                        &[
+                            (
+                                "starttls-command",
+                                call.get_flag_value("starttls-command")
+                                    .is_some(),
+                            ),
+                            (
+                                "starttls-expect",
+                                call.get_flag_value("starttls-expect")
+                                    .is_some(),
+                            ),
+                            (
+                                "starttls-greeting",
+                                call.has_flag("starttls-greeting")?,
+                            ),
+                        ],
+                        head,
+                    )?;
+                    None
+                }
+            };
+        let use_tls = call.has_flag("tls")?;
+        if use_tls && starttls.is_some() {
+            reject_flags("tls", &[("starttls", true)], head)?;
+        }
+        let proxy_protocol = call.has_flag("proxy-protocol")?;
+        let proxy = match call.get_flag::<Spanned<String>>("proxy")? {
+            Some(url) => Some(Spanned {
+                item: Proxy::parse(&url.item).map_err(|e| {
+                    LabeledError::new("Invalid proxy")
+                        .with_help(e)
+                        .with_label("here", url.span)
+                })?,
+                span: url.span,
+            }),
+            None => None,
+        };
+        if proxy_protocol {
+            // The header would describe the connection to the proxy.
+            reject_flags(
+                "proxy-protocol",
+                &[("proxy", proxy.is_some())],
+                head,
+            )?;
+        }
+        let tls_sni: Option<Spanned<String>> =
+            call.get_flag("tls-sni")?;
+        let tls_alpn: Option<Spanned<Vec<String>>> =
+            call.get_flag("tls-alpn")?;
+        let client_cert: Option<Spanned<String>> =
+            call.get_flag("client-cert")?;
+        let client_key: Option<Spanned<String>> =
+            call.get_flag("client-key")?;
+        let insecure = call.has_flag("insecure")?;
+        let session_cache = call.has_flag("tls-session-cache")?;
+        let early_data = call.has_flag("early-data")?;
+        if !session_cache {
+            require_flags(
+                "tls-session-cache",
+                &[("early-data", early_data)],
+                head,
+            )?;
+        }
+        let collect = call.has_flag("collect")?;
+        let structured = call.has_flag("structured")?;
+        let count = match call.get_flag::<Spanned<i64>>("count")? {
+            Some(count) if count.item < 1 => {
+                return Err(LabeledError::new("Invalid count")
+                    .with_help("--count must be at least 1.")
+                    .with_label("here", count.span))
+            }
+            Some(count) => Some(count.item as usize),
+            None => None,
+        };
+        if !collect {
+            require_flags(
+                "collect",
+                &[
+                    ("structured", structured),
+                    ("count", count.is_some()),
+                ],
+                head,
+            )?;
+        }
+        let with_source = call.has_flag("with-source")?;
+        if with_source {
+            reject_flags("with-source", &[("collect", collect)], head)?;
+        }
+        let from_json = call.has_flag("from-json")?;
+        let broadcast = call.has_flag("broadcast")?;
+        let multicast: Option<Spanned<String>> =
+            call.get_flag("multicast")?;
+        if !use_udp {
+            require_flags(
+                "udp",
+                &[
+                    ("collect", collect),
+                    ("broadcast", broadcast),
+                    ("multicast", multicast.is_some()),
+                    ("with-source", with_source),
+                    ("from-json", from_json),
+                ],
+                head,
+            )?;
+        }
+        if use_udp {
+            reject_flags(
+                "udp",
+                &[
+                    ("no-delay", no_delay),
+                    ("keepalive", keepalive.is_some()),
+                    ("shutdown-write", shutdown_write),
+                    ("tls", use_tls),
+                    ("starttls", starttls.is_some()),
+                    ("proxy", proxy.is_some()),
+                    ("proxy-protocol", proxy_protocol),
+                    (
+                        "deadline",
+                        call.get_flag_value("deadline").is_some(),
+                    ),
+                    (
+                        "output-file",
+                        call.get_flag_value("output-file").is_some(),
+                    ),
+                ],
+                head,
+            )?;
+        }
+        if use_unix {
+            reject_flags(
+                "unix",
+                &[
+                    ("udp", use_udp),
+                    ("no-delay", no_delay),
+                    ("keepalive", keepalive.is_some()),
+                    ("bind", call.get_flag_value("bind").is_some()),
+                    (
+                        "local-port",
+                        call.get_flag_value("local-port").is_some(),
+                    ),
+                    ("ttl", call.get_flag_value("ttl").is_some()),
+                    (
+                        "recv-buffer",
+                        call.get_flag_value("recv-buffer").is_some(),
+                    ),
+                    (
+                        "send-buffer",
+                        call.get_flag_value("send-buffer").is_some(),
+                    ),
+                    ("tls", use_tls),
+                    ("starttls", starttls.is_some()),
+                    ("proxy", proxy.is_some()),
+                    ("proxy-protocol", proxy_protocol),
+                    ("no-resolve", call.has_flag("no-resolve")?),
+                ],
+                head,
+            )?;
+        }
+        // The port may come with the host, as in `example.com:443`.
+        // Brackets around an IPv6 literal are accepted, but the TLS
+        // server name and the proxy need the bare address.
+        let (host, port_in_host) = if use_unix {
+            (host, None)
+        } else {
+            match split_port(&host) {
+                Some((name, port)) => (
+                    unbracket(name).to_string(),
+                    Some(port.to_string()),
+                ),
+                None => (unbracket(&host).to_string(), None),
+            }
+        };
+        // A `tls` default from the config only applies to plain TCP.
+        // --starttls sets up the same TLS, only started later.
+        let use_tls = use_tls
+            || starttls.is_some()
+            || (config.tls && !use_udp && !use_unix);
+        if let (Some(sni), false) = (&tls_sni, use_tls) {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help(
+                    "--tls-sni only applies together with --tls.",
+                )
+                .with_label("here", sni.span));
+        }
+        if let (Some(alpn), false) = (&tls_alpn, use_tls) {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help(
+                    "--tls-alpn only applies together with --tls.",
+                )
+                .with_label("here", alpn.span));
+        }
+        let client_auth = match (client_cert, client_key) {
+            (Some(cert), Some(key)) => {
+                if !use_tls {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help(
+                            "--client-cert only applies together with --tls.",
+                        )
+                        .with_label("here", cert.span));
+                }
+                Some((cert, key))
             }
+            (Some(flag), None) | (None, Some(flag)) => {
+                return Err(LabeledError::new("Incomplete TLS setup")
+                    .with_help("--client-cert and --client-key must be given together.")
+                    .with_label("here", flag.span));
+            }
+            (None, None) => None,
         };
+        if insecure && !use_tls {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help(
+                    "--insecure only applies together with --tls.",
+                )
+                .with_label("here", head));
+        }
+        if session_cache && !use_tls {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help(
+                    "--tls-session-cache only applies together with --tls.",
+                )
+                .with_label("here", head));
+        }
 
-        let addr = format!("{}:{}", host, port);
-        let socket_addr: SocketAddr = addr
-            .to_socket_addrs()
-            .map_err(|e| {
-                LabeledError::new("Failed to resolve host")
-                    .with_help(e.to_string())
-                    .with_label(
-                        "for this host",
-                        call.positional[0].span(),
-                    )
-            })?
-            .next()
-            .ok_or_else(|| {
-                LabeledError::new("No IP addresses found for host")
-                    .with_label(
-                        "for this host",
-                        call.positional[0].span(),
-                    )
-            })?;
+        // What a --reuse connection must have been opened with.
+        let tls_key = use_tls.then(|| TlsKey {
+            server_name: tls_sni
+                .as_ref()
+                .map_or_else(|| host.clone(), |sni| sni.item.clone()),
+            insecure,
+            alpn: tls_alpn
+                .as_ref()
+                .map(|alpn| alpn.item.clone())
+                .unwrap_or_default(),
+            client_cert: client_auth
+                .as_ref()
+                .map(|(cert, _)| PathBuf::from(&cert.item)),
+        });
 
-        if use_udp {
-            // --- UDP LOGIC (FIXED) ---
-            let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| {
-                LabeledError::new("Failed to bind UDP socket")
-                    .with_help(e.to_string())
-                    .with_label("here", head)
+        // Set up TLS before connecting, so configuration problems are
+        // reported without touching the network.
+        let tls_setup = if use_tls {
+            let (name, span) = match &tls_sni {
+                Some(sni) => (sni.item.as_str(), sni.span),
+                None => (host.as_str(), call.positional[0].span()),
+            };
+            let server_name = tls::server_name(name).map_err(|e| {
+                LabeledError::new("Invalid TLS server name")
+                    .with_help(e)
+                    .with_label("here", span)
             })?;
-            socket.set_read_timeout(Some(timeout)).map_err(|e| {
-                LabeledError::new("Failed to set UDP read timeout")
-                    .with_help(e.to_string())
-                    .with_label("here", head)
-            })?;
-
-            // 1. Use `send_to` to send the data to the destination.
-            socket.send_to(&input_bytes, socket_addr).map_err(|e| {
-                LabeledError::new("Failed to send UDP packet")
-                    .with_help(e.to_string())
+            let alpn = tls_alpn
+                .map(|alpn| {
+                    alpn.item
+                        .into_iter()
+                        .map(String::into_bytes)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let identity = match client_auth {
+                Some((cert, key)) => {
+                    let cwd = PathBuf::from(engine.get_current_dir()?);
+                    let identity = tls::load_identity(
+                        &cwd.join(&cert.item),
+                        &cwd.join(&key.item),
+                    )
+                    .map_err(|e| {
+                        LabeledError::new(
+                            "Failed to load client certificate",
+                        )
+                        .with_help(e)
+                        .with_label("here", cert.span)
+                    })?;
+                    Some(identity)
+                }
+                None => None,
+            };
+            let config = tls::client_config(
+                insecure,
+                alpn,
+                identity,
+                session_cache,
+                early_data,
+            )
+            .map_err(|e| {
+                LabeledError::new("Failed to set up TLS")
+                    .with_help(e)
                     .with_label("here", head)
             })?;
+            Some((server_name, config))
+        } else {
+            None
+        };
 
-            let mut buffer = vec![0u8; 65535];
+        // The config's timeouts are checked to be positive on loading.
+        let timeout = positive_duration(call, "timeout")?
+            .unwrap_or_else(|| {
+                Duration::from_nanos(
+                    config.timeout.unwrap_or(10_000_000_000) as u64,
+                )
+            });
+        let read_timeout = positive_duration(call, "read-timeout")?
+            .or_else(|| {
+                config
+                    .read_timeout
+                    .map(|nanos| Duration::from_nanos(nanos as u64))
+            })
+            .unwrap_or(timeout);
 
-            // 2. Use `recv_from` to get the reply from ANY source IP.
-            let (bytes_read, _source_addr) =
-                socket.recv_from(&mut buffer).map_err(|e| {
-                    LabeledError::new(
-                        "Failed to receive UDP packet (timed out?)",
+        let ttl = match call.get_flag::<Spanned<i64>>("ttl")? {
+            Some(ttl) => Some(u32::try_from(ttl.item).map_err(|_| {
+                LabeledError::new("Invalid TTL")
+                    .with_help(
+                        "--ttl must be a non-negative number that fits in 32 bits.",
+                    )
+                    .with_label("here", ttl.span)
+            })?),
+            None => None,
+        };
+
+        let buffers = BufferSizes {
+            recv: buffer_size(call.get_flag("recv-buffer")?)?,
+            send: buffer_size(call.get_flag("send-buffer")?)?,
+        };
+
+        let keepalive = match keepalive {
+            Some(idle) => {
+                // The system counts keepalive time in whole seconds.
+                let interval = match keepalive_interval {
+                    Some(interval) if interval.item < 1_000_000_000 => {
+                        return Err(LabeledError::new(
+                            "Invalid keepalive interval",
+                        )
+                        .with_help(
+                            "--keepalive-interval must be at least 1sec.",
+                        )
+                        .with_label("here", interval.span))
+                    }
+                    Some(interval) => {
+                        Some(Duration::from_nanos(interval.item as u64))
+                    }
+                    None => None,
+                };
+                let retries = match keepalive_retries {
+                    Some(retries) => Some(
+                        u32::try_from(retries.item)
+                            .ok()
+                            .filter(|&retries| retries > 0)
+                            .ok_or_else(|| {
+                                LabeledError::new(
+                                    "Invalid keepalive retries",
+                                )
+                                .with_help(
+                                    "--keepalive-retries must be a positive number.",
+                                )
+                                .with_label("here", retries.span)
+                            })?,
+                    ),
+                    None => None,
+                };
+                let (keepalive, ignored) = keepalive_probes(
+                    TcpKeepalive::new().with_time(idle),
+                    interval,
+                    retries,
+                );
+                if verbose {
+                    for flag in ignored {
+                        eprintln!(
+                            "* --{} is not supported on this platform and is ignored",
+                            flag
+                        );
+                    }
+                }
+                Some((keepalive, (idle, interval, retries)))
+            }
+            None => None,
+        };
+        // The settings are kept apart for --reuse, as a `TcpKeepalive`
+        // cannot be compared.
+        let (keepalive, keepalive_settings) = keepalive.unzip();
+
+        let retries: Option<Spanned<i64>> = call.get_flag("retries")?;
+        let retry_delay = positive_duration(call, "retry-delay")?;
+        let retries = match retries {
+            Some(retries) => {
+                if use_udp || use_unix {
+                    return Err(LabeledError::new(
+                        "Invalid flag combination",
+                    )
+                    .with_help(
+                        "--retries only applies to TCP connections.",
+                    )
+                    .with_label("here", retries.span));
+                }
+                u32::try_from(retries.item).map_err(|_| {
+                    LabeledError::new("Invalid retry count")
+                        .with_help("--retries must be zero or more.")
+                        .with_label("here", retries.span)
+                })?
+            }
+            None => 0,
+        };
+        let retry_delay = retry_delay.unwrap_or(Duration::from_secs(1));
+
+        let max_size =
+            match call.get_flag::<Spanned<i64>>("max-size")? {
+                Some(size) if size.item < 1 => {
+                    return Err(LabeledError::new("Invalid size")
+                        .with_help("--max-size must be positive.")
+                        .with_label("here", size.span))
+                }
+                Some(size) => Some(size.item as u64),
+                None => None,
+            };
+        let truncate = call.has_flag("truncate")?;
+        if max_size.is_none() {
+            require_flags("max-size", &[("truncate", truncate)], head)?;
+        }
+        let read_until = match call.get_flag::<Value>("read-until")? {
+            Some(Value::String { val, .. }) => Some(val.into_bytes()),
+            Some(Value::Binary { val, .. }) => Some(val),
+            Some(other) => {
+                return Err(LabeledError::new("Invalid delimiter")
+                    .with_help(format!(
+                "--read-until takes a string or binary, but got {}.",
+                other.get_type()
+            ))
+                    .with_label("here", other.span()))
+            }
+            None => None,
+        };
+        if let Some(delimiter) = &read_until {
+            if delimiter.is_empty() {
+                return Err(LabeledError::new("Invalid delimiter")
+                    .with_help("--read-until cannot be empty.")
+                    .with_label("here", head));
+            }
+            if use_udp {
+                reject_flags("udp", &[("read-until", true)], head)?;
+            }
+        }
+        let length_prefix = match call.get_flag("length-prefixed")? {
+            Some(width) => Some(framing::prefix_width(width)?),
+            None => None,
+        };
+        let split_frames = call.has_flag("output-split-frames")?;
+        let strict = call.has_flag("strict")?;
+        if length_prefix.is_none() {
+            require_flags(
+                "length-prefixed",
+                &[("output-split-frames", split_frames)],
+                head,
+            )?;
+        }
+        if !split_frames {
+            require_flags(
+                "output-split-frames",
+                &[("strict", strict)],
+                head,
+            )?;
+        }
+        let max_rate = match call.get_flag::<Spanned<i64>>("max-rate")? {
+            Some(rate) if rate.item < 1 => {
+                return Err(LabeledError::new("Invalid rate")
+                    .with_help("--max-rate must be at least 1 byte per second.")
+                    .with_label("here", rate.span))
+            }
+            Some(rate) => {
+                if use_udp {
+                    reject_flags("udp", &[("max-rate", true)], head)?;
+                }
+                Some(rate.item as u64)
+            }
+            None => None,
+        };
+        let first_line = call.has_flag("first-line")?;
+        if first_line {
+            reject_flags(
+                "first-line",
+                &[
+                    ("udp", use_udp),
+                    ("read-until", read_until.is_some()),
+                    ("length-prefixed", length_prefix.is_some()),
+                    (
+                        "output-file",
+                        call.get_flag_value("output-file").is_some(),
+                    ),
+                ],
+                head,
+            )?;
+        }
+        let hex_output = call.has_flag("hex-output")?;
+        if hex_output {
+            reject_flags(
+                "hex-output",
+                &[
+                    ("first-line", first_line),
+                    ("from-json", from_json),
+                    (
+                        "output-file",
+                        call.get_flag_value("output-file").is_some(),
+                    ),
+                ],
+                head,
+            )?;
+        }
+        let reuse = call.has_flag("reuse")?;
+        if reuse {
+            reject_flags(
+                "reuse",
+                &[
+                    ("udp", use_udp),
+                    ("unix", use_unix),
+                    ("shutdown-write", shutdown_write),
+                    ("proxy", proxy.is_some()),
+                ],
+                head,
+            )?;
+            if read_until.is_none()
+                && length_prefix.is_none()
+                && !first_line
+            {
+                return Err(LabeledError::new("Invalid flag combination")
+                    .with_help("--reuse needs to know where a reply ends without the connection closing. Use it with --read-until, --length-prefixed or --first-line.")
+                    .with_label("here", head));
+            }
+        }
+        if length_prefix.is_some() {
+            reject_flags(
+                "length-prefixed",
+                &[
+                    ("udp", use_udp),
+                    ("read-until", read_until.is_some()),
+                    ("truncate", truncate),
+                ],
+                head,
+            )?;
+        }
+        let interactive = call.has_flag("interactive")?;
+        if interactive {
+            reject_flags(
+                "interactive",
+                &[
+                    ("udp", use_udp),
+                    ("starttls", starttls.is_some()),
+                    ("tls", use_tls),
+                    ("reuse", reuse),
+                    ("length-prefixed", length_prefix.is_some()),
+                    ("first-line", first_line),
+                    ("hex-output", hex_output),
+                    ("max-rate", max_rate.is_some()),
+                ],
+                head,
+            )?;
+        }
+        let deadline = positive_duration(call, "deadline")?;
+        let content_type: Option<String> =
+            call.get_flag("content-type")?;
+        let output_file =
+            match call.get_flag::<Spanned<String>>("output-file")? {
+                Some(path) => Some(Spanned {
+                    item: PathBuf::from(engine.get_current_dir()?)
+                        .join(path.item),
+                    span: path.span,
+                }),
+                None => None,
+            };
+        let repeat = match call.get_flag::<Spanned<i64>>("repeat")? {
+            Some(times) => {
+                if times.item < 1 {
+                    return Err(LabeledError::new(
+                        "Invalid repeat count",
                     )
+                    .with_help("--repeat must be at least 1.")
+                    .with_label("here", times.span));
+                }
+                reject_flags(
+                    "repeat",
+                    &[
+                        ("udp", use_udp),
+                        ("interactive", interactive),
+                        ("reuse", reuse),
+                        ("first-line", first_line),
+                        ("output-file", output_file.is_some()),
+                        (
+                            "line-delay",
+                            call.get_flag_value("line-delay").is_some(),
+                        ),
+                    ],
+                    head,
+                )?;
+                Some(Repeat {
+                    times: times.item as u64,
+                    delay: positive_duration(call, "repeat-delay")?
+                        .unwrap_or(Duration::ZERO),
+                })
+            }
+            None => {
+                require_flags(
+                    "repeat",
+                    &[(
+                        "repeat-delay",
+                        call.get_flag_value("repeat-delay").is_some(),
+                    )],
+                    head,
+                )?;
+                None
+            }
+        };
+        if split_frames {
+            reject_flags(
+                "output-split-frames",
+                &[
+                    ("reuse", reuse),
+                    ("repeat", repeat.is_some()),
+                    ("output-file", output_file.is_some()),
+                ],
+                head,
+            )?;
+        }
+        let exchange = Exchange {
+            read_timeout,
+            deadline,
+            shutdown_write,
+            interactive,
+            read_until,
+            length_prefix,
+            split_frames,
+            strict,
+            first_line,
+            hex_output,
+            from_json,
+            max_rate,
+            max_size: max_size.map(|bytes| MaxSize { bytes, truncate }),
+            content_type,
+            output_file,
+            repeat,
+            complete: Arc::new(AtomicBool::new(false)),
+            leftover: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let crlf = call.has_flag("crlf")?;
+        let line_delay = positive_duration(call, "line-delay")?;
+        if line_delay.is_some() {
+            reject_flags(
+                "line-delay",
+                &[
+                    ("udp", use_udp),
+                    ("length-prefixed", length_prefix.is_some()),
+                ],
+                head,
+            )?;
+        }
+        let encoding = match call.get_flag("input-encoding")? {
+            Some(name) => {
+                reject_flags(
+                    "input-encoding",
+                    &[
+                        ("crlf", crlf),
+                        ("line-delay", line_delay.is_some()),
+                    ],
+                    head,
+                )?;
+                Some(InputEncoding::parse(&name)?)
+            }
+            None => None,
+        };
+        let payload = match call
+            .get_flag::<Spanned<String>>("send-file")?
+        {
+            Some(path) => {
+                reject_flags(
+                    "send-file",
+                    &[
+                        ("crlf", crlf),
+                        ("line-delay", line_delay.is_some()),
+                        ("input-encoding", encoding.is_some()),
+                    ],
+                    head,
+                )?;
+                if !matches!(
+                    input,
+                    PipelineData::Empty
+                        | PipelineData::Value(Value::Nothing { .. }, _)
+                ) {
+                    return Err(LabeledError::new("Input given twice")
+                        .with_help("Send either the pipeline input or --send-file, not both.")
+                        .with_label("here", path.span));
+                }
+                let cwd = PathBuf::from(engine.get_current_dir()?);
+                let file =
+                    File::open(cwd.join(&path.item)).map_err(|e| {
+                        LabeledError::new("Failed to open file")
+                            .with_help(format!("{}: {}", path.item, e))
+                            .with_label("here", path.span)
+                    })?;
+                Payload::Stream(Box::new(file))
+            }
+            None => Payload::from_input(
+                input,
+                crlf,
+                line_delay,
+                encoding,
+                call.has_flag("auto-json")?,
+                split_lists,
+                head,
+            )?,
+        };
+        if let Payload::Messages(_) = payload {
+            let rejected = [
+                ("udp", use_udp),
+                ("interactive", interactive),
+                ("reuse", reuse),
+                ("first-line", first_line),
+                ("output-file", exchange.output_file.is_some()),
+                ("line-delay", line_delay.is_some()),
+                ("early-data", early_data),
+                ("output-split-frames", split_frames),
+            ];
+            if let Some((flag, _)) =
+                rejected.iter().find(|(_, set)| *set)
+            {
+                return Err(LabeledError::new("Invalid flag combination")
+                    .with_help(format!(
+                        "--{} cannot be used with a list input, which is sent as separate messages.",
+                        flag
+                    ))
+                    .with_label("here", head));
+            }
+        }
+
+        // Early data goes out with the handshake, before the connection
+        // could be taken from the pool, and as the bytes of one message.
+        let mut payload = if early_data {
+            reject_flags(
+                "early-data",
+                &[
+                    ("reuse", reuse),
+                    ("length-prefixed", length_prefix.is_some()),
+                    ("repeat", exchange.repeat.is_some()),
+                    ("line-delay", line_delay.is_some()),
+                ],
+                head,
+            )?;
+            payload.into_bytes().map(Payload::Bytes).map_err(|e| {
+                LabeledError::new("Failed to read the input")
                     .with_help(e.to_string())
                     .with_label("here", head)
-                })?;
+            })?
+        } else {
+            payload
+        };
 
-            buffer.truncate(bytes_read);
+        if use_unix {
+            return connect_unix(
+                &host,
+                payload,
+                &exchange,
+                read_timeout,
+                engine,
+                head,
+            );
+        }
 
-            Ok(PipelineData::Value(Value::binary(buffer, head), None))
-        } else {
-            // --- TCP LOGIC (unchanged) ---
-            let mut stream =
-                TcpStream::connect_timeout(&socket_addr, timeout)
+        let port_arg: Option<Spanned<i64>> = call.opt(1)?;
+        let port: u16 = match (port_in_host, port_arg) {
+            (Some(_), Some(port)) => {
+                return Err(LabeledError::new("Port given twice")
+                    .with_help("Give the port either with the host, as in host:port, or as a separate argument, not both.")
+                    .with_label("second port", port.span))
+            }
+            (Some(port), None) => port.parse().map_err(|e| {
+                LabeledError::new("Invalid port number")
+                    .with_help(format!(
+                        "Port must be between 0 and 65535. Error: {}",
+                        e
+                    ))
+                    .with_label("here", call.positional[0].span())
+            })?,
+            (None, Some(port)) => port.item.try_into().map_err(|e| {
+                LabeledError::new("Invalid port number")
+                    .with_help(format!(
+                        "Port must be between 0 and 65535. Error: {}",
+                        e
+                    ))
+                    .with_label("here", port.span)
+            })?,
+            (None, None) => {
+                return Err(LabeledError::new("Missing port")
+                    .with_help("A port is required unless --unix is given. Pass it as a second argument or as host:port.")
+                    .with_label("for this host", call.positional[0].span()))
+            }
+        };
+
+        let family = Family::from_flags(call)?;
+        let host_span = call.positional[0].span();
+        if call.has_flag("no-resolve")? {
+            let mut hosts = vec![(host.as_str(), host_span)];
+            if let Some(proxy) = &proxy {
+                hosts.push((proxy.item.host.as_str(), proxy.span));
+            }
+            for (name, span) in hosts {
+                if unbracket(name).parse::<IpAddr>().is_err() {
+                    return Err(LabeledError::new("Not an IP address")
+                        .with_help(format!(
+                            "With --no-resolve, `{}` would have to be looked up in DNS. Give an IP address instead.",
+                            name
+                        ))
+                        .with_label("here", span));
+                }
+            }
+        }
+        let resolve_started = Instant::now();
+        // Through a proxy, the connection is made to the proxy, which is
+        // then asked to reach the destination.
+        let (mut socket_addrs, destination) = match &proxy {
+            Some(proxy) => {
+                let destination = if proxy.item.remote_dns {
+                    if family.is_some() {
+                        return Err(LabeledError::new(
+                            "Invalid flag combination",
+                        )
+                        .with_help("--ipv4 and --ipv6 cannot be used with a socks5h:// or http:// proxy, which resolves host itself.")
+                        .with_label("here", proxy.span));
+                    }
+                    Destination::Name(host.clone(), port)
+                } else {
+                    Destination::Addr(
+                        resolve(&host, port, family, host_span)?[0],
+                    )
+                };
+                (
+                    resolve(
+                        &proxy.item.host,
+                        proxy.item.port,
+                        None,
+                        proxy.span,
+                    )?,
+                    Some(destination),
+                )
+            }
+            None => (resolve(&host, port, family, host_span)?, None),
+        };
+        if verbose {
+            let resolved = match &proxy {
+                Some(proxy) => {
+                    format!("{}:{}", proxy.item.host, proxy.item.port)
+                }
+                None => host_port(&host, port),
+            };
+            let candidates: Vec<String> = socket_addrs
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect();
+            eprintln!(
+                "* Resolved {} in {:?}: {}",
+                resolved,
+                resolve_started.elapsed(),
+                candidates.join(", ")
+            );
+        }
+
+        let bind_addr = match call
+            .get_flag::<Spanned<String>>("bind")?
+            .or(config.bind)
+        {
+            Some(bind) => {
+                let local =
+                    parse_bind_addr(&bind.item).map_err(|e| {
+                        LabeledError::new("Invalid bind address")
+                            .with_help(e.to_string())
+                            .with_label("here", bind.span)
+                    })?;
+                let first = socket_addrs[0];
+                socket_addrs
+                    .retain(|addr| addr.is_ipv4() == local.is_ipv4());
+                if socket_addrs.is_empty() {
+                    return Err(LabeledError::new(
+                        "Address family mismatch",
+                    )
+                    .with_help(format!(
+                        "Cannot send from {} to {}. Use a bind address of the same IP version as the destination.",
+                        local, first
+                    ))
+                    .with_label("this address", bind.span));
+                }
+                Some(local)
+            }
+            None => None,
+        };
+        let local_port = match call
+            .get_flag::<Spanned<i64>>("local-port")?
+        {
+            Some(port) => {
+                let Some(number) =
+                    u16::try_from(port.item).ok().filter(|&p| p > 0)
+                else {
+                    return Err(LabeledError::new("Invalid port")
+                        .with_help(
+                            "--local-port must be between 1 and 65535.",
+                        )
+                        .with_label("here", port.span));
+                };
+                if bind_addr.is_some_and(|local| local.port() != 0) {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help("The local port is given both in --bind and with --local-port. Give it only once.")
+                        .with_label("here", port.span));
+                }
+                Some(number)
+            }
+            None => None,
+        };
+        let bind_addr = bind_addr.map(|mut local| {
+            if let Some(port) = local_port {
+                local.set_port(port);
+            }
+            local
+        });
+
+        // UDP has no handshake to tell a dead address from a live one,
+        // so it always uses the first.
+        let socket_addr = socket_addrs[0];
+        if use_udp && verbose {
+            eprintln!("* Sending to {}", socket_addr);
+        }
+
+        if use_udp {
+            // --- UDP LOGIC (FIXED) ---
+            let local = bind_addr.unwrap_or_else(|| {
+                let mut local = unspecified_addr(&socket_addr);
+                local.set_port(local_port.unwrap_or(0));
+                local
+            });
+            let socket = UdpSocket::bind(local).map_err(|e| {
+                LabeledError::new("Failed to bind UDP socket")
+                    .with_help(local_bind_error(local, e).to_string())
+                    .with_label("here", head)
+            })?;
+            socket.set_read_timeout(Some(read_timeout)).map_err(
+                |e| {
+                    LabeledError::new("Failed to set UDP read timeout")
+                        .with_help(e.to_string())
+                        .with_label("here", head)
+                },
+            )?;
+
+            if let Some(ttl) = ttl {
+                set_ttl(SockRef::from(&socket), ttl, socket_addr)
                     .map_err(|e| {
+                        LabeledError::new("Failed to set TTL")
+                            .with_help(e.to_string())
+                            .with_label("here", head)
+                    })?;
+            }
+
+            if let Some(group) = &multicast {
+                join_multicast(&socket, &group.item, local).map_err(
+                    |e| {
                         LabeledError::new(
-                            "Connection timed out or failed",
+                            "Failed to join multicast group",
                         )
+                        .with_help(e)
+                        .with_label("here", group.span)
+                    },
+                )?;
+            }
+
+            buffers.apply(SockRef::from(&socket)).map_err(|e| {
+                LabeledError::new("Failed to set socket buffer size")
+                    .with_help(e.to_string())
+                    .with_label("here", head)
+            })?;
+            if verbose {
+                report_buffers(SockRef::from(&socket));
+            }
+
+            if broadcast {
+                socket.set_broadcast(true).map_err(|e| {
+                    LabeledError::new("Failed to enable UDP broadcast")
                         .with_help(e.to_string())
                         .with_label("here", head)
-                    })?;
-            stream.set_read_timeout(Some(timeout)).map_err(|e| {
-                LabeledError::new("Failed to set read timeout")
+                })?;
+            }
+
+            // A datagram has to be sent in one piece, so a streamed
+            // input is collected first.
+            let input_bytes = payload.into_bytes().map_err(|e| {
+                LabeledError::new("Failed to read input")
+                    .with_help(e.to_string())
+                    .with_label("input originates from here", head)
+            })?;
+            let (max_datagram, unfragmented) = if socket_addr.is_ipv4()
+            {
+                (MAX_DATAGRAM_V4, UNFRAGMENTED_V4)
+            } else {
+                (MAX_DATAGRAM_V6, UNFRAGMENTED_V6)
+            };
+            if input_bytes.len() > max_datagram {
+                return Err(LabeledError::new("Datagram too large")
+                    .with_help(format!(
+                        "The input is {} bytes, but a UDP datagram over {} can carry at most {}. Send it over TCP instead, or split it into several datagrams.",
+                        input_bytes.len(),
+                        if socket_addr.is_ipv4() { "IPv4" } else { "IPv6" },
+                        max_datagram
+                    ))
+                    .with_label("input originates from here", head));
+            }
+            if input_bytes.len() > unfragmented {
+                eprintln!(
+                    "Warning: the {}-byte datagram does not fit in a 1500-byte Ethernet frame, which holds {} bytes of UDP payload, so it will likely be fragmented. Fragments are often dropped along the way.",
+                    input_bytes.len(),
+                    unfragmented
+                );
+            }
+
+            // 1. Use `send_to` to send the data to the destination.
+            socket.send_to(&input_bytes, socket_addr).map_err(|e| {
+                LabeledError::new("Failed to send UDP packet")
                     .with_help(e.to_string())
                     .with_label("here", head)
             })?;
 
-            stream.write_all(&input_bytes).map_err(|e| {
-                LabeledError::new("Failed to write to socket")
+            if collect {
+                let replies = collect_datagrams(
+                    &socket,
+                    read_timeout,
+                    count,
+                    engine.signals(),
+                )
+                .map_err(|e| {
+                    LabeledError::new(
+                        "Failed to receive UDP packet (timed out?)",
+                    )
                     .with_help(e.to_string())
                     .with_label("here", head)
+                })?;
+                let custom = connection_record(
+                    Some(socket_addr),
+                    socket.local_addr().ok(),
+                    head,
+                );
+                let output = if structured {
+                    let records = replies
+                        .into_iter()
+                        .map(|(from, data)| {
+                            Ok(Value::record(
+                                record! {
+                                    "from" => Value::string(from.to_string(), head),
+                                    "data" => exchange.reply_value(
+                                        exchange.limit_reply(data, head)?,
+                                        head,
+                                    ),
+                                },
+                                head,
+                            ))
+                        })
+                        .collect::<Result<Vec<_>, LabeledError>>()?;
+                    Value::list(records, head)
+                } else {
+                    let data = replies
+                        .into_iter()
+                        .flat_map(|(_, data)| data)
+                        .collect();
+                    exchange.reply_value(
+                        exchange.limit_reply(data, head)?,
+                        head,
+                    )
+                };
+                return Ok(PipelineData::Value(
+                    output,
+                    exchange.reply_metadata(custom),
+                ));
+            }
+
+            let mut buffer = vec![0u8; 65535];
+
+            // 2. Use `recv_from` to get the reply from ANY source IP.
+            let (bytes_read, source_addr) = recv_interruptible(
+                &socket,
+                &mut buffer,
+                read_timeout,
+                engine.signals(),
+            )
+            .map_err(|e| {
+                LabeledError::new(
+                    "Failed to receive UDP packet (timed out?)",
+                )
+                .with_help(e.to_string())
+                .with_label("here", head)
             })?;
 
-            let source = ByteStreamSource::Read(Box::new(stream));
-            let signals = engine.signals().clone();
-            let byte_stream = ByteStream::new(
-                source,
+            buffer.truncate(bytes_read);
+            let buffer = exchange.limit_reply(buffer, head)?;
+
+            let custom = connection_record(
+                Some(source_addr),
+                socket.local_addr().ok(),
+                head,
+            );
+            let data = exchange.reply_value(buffer, head);
+            let output = if with_source {
+                Value::record(
+                    record! {
+                        "from_addr" => Value::string(source_addr.ip().to_string(), head),
+                        "from_port" => Value::int(source_addr.port().into(), head),
+                        "data" => data,
+                    },
+                    head,
+                )
+            } else {
+                data
+            };
+            Ok(PipelineData::Value(
+                output,
+                exchange.reply_metadata(custom),
+            ))
+        } else {
+            // --- TCP LOGIC ---
+            // An idle connection left by an earlier --reuse call skips
+            // connecting and the TLS handshake altogether.
+            let pool_key = reuse.then(|| PoolKey {
+                host: host.clone(),
+                port,
+                bind: bind_addr,
+                local_port,
+                proxy_protocol,
+                ttl,
+                no_delay,
+                keepalive: keepalive_settings,
+                recv_buffer: buffers.recv,
+                send_buffer: buffers.send,
+                tls: tls_key,
+            });
+            let (reused, pending) = pool_key
+                .as_ref()
+                .and_then(|key| plugin.pool.take(key))
+                .unzip();
+            let was_reused = reused.is_some();
+            let mut early_accepted = false;
+            let (connection, deadline) = match reused {
+                Some(connection) => {
+                    if verbose {
+                        if let Ok(peer) = connection.tcp().peer_addr() {
+                            eprintln!(
+                                "* Reusing the connection to {}",
+                                peer
+                            );
+                        }
+                    }
+                    let deadline = start_deadline(
+                        exchange.deadline,
+                        connection.tcp(),
+                        head,
+                    )?;
+                    (connection, deadline)
+                }
+                None => {
+                    let connect_started = Instant::now();
+                    let options = TcpOptions {
+                        bind: bind_addr,
+                        local_port,
+                        ttl,
+                        keepalive,
+                        buffers,
+                    };
+                    let mut attempts = 0;
+                    let stream = loop {
+                        attempts += 1;
+                        let connected = connect_any(
+                            &socket_addrs,
+                            &options,
+                            timeout,
+                        )
+                        .and_then(|mut stream| {
+                            if let (Some(proxy), Some(destination)) =
+                                (&proxy, &destination)
+                            {
+                                proxy
+                                    .item
+                                    .open_tunnel(
+                                        &mut stream,
+                                        destination,
+                                        timeout,
+                                    )
+                                    .map_err(|e| {
+                                        format!(
+                                            "proxy {}:{}: {}",
+                                            proxy.item.host,
+                                            proxy.item.port,
+                                            e
+                                        )
+                                    })?;
+                            }
+                            Ok(stream)
+                        });
+                        match connected {
+                            Ok(stream) => break stream,
+                            Err(e)
+                                if attempts <= retries
+                                    && !engine
+                                        .signals()
+                                        .interrupted() =>
+                            {
+                                eprintln!(
+                                    "Connection attempt {} failed, retrying: {}",
+                                    attempts, e
+                                );
+                                pause(retry_delay, engine.signals());
+                                if engine.signals().interrupted() {
+                                    return Err(LabeledError::new(
+                                        "Connection interrupted",
+                                    )
+                                    .with_help(format!(
+                                        "Stopped retrying after {} failed attempts.",
+                                        attempts
+                                    ))
+                                    .with_label("here", head));
+                                }
+                            }
+                            Err(e) => {
+                                let message = if attempts > 1 {
+                                    format!(
+                                        "Connection failed after {} attempts",
+                                        attempts
+                                    )
+                                } else {
+                                    "Connection timed out or failed"
+                                        .to_string()
+                                };
+                                return Err(LabeledError::new(message)
+                                    .with_help(e)
+                                    .with_label("here", head));
+                            }
+                        }
+                    };
+                    stream
+                        .set_read_timeout(Some(read_timeout))
+                        .map_err(|e| {
+                            LabeledError::new(
+                                "Failed to set read timeout",
+                            )
+                            .with_help(e.to_string())
+                            .with_label("here", head)
+                        })?;
+                    if no_delay {
+                        stream.set_nodelay(true).map_err(|e| {
+                            LabeledError::new(
+                                "Failed to set TCP_NODELAY",
+                            )
+                            .with_help(e.to_string())
+                            .with_label("here", head)
+                        })?;
+                    }
+
+                    if verbose {
+                        if let Ok(peer) = stream.peer_addr() {
+                            eprintln!(
+                                "* Connected to {} in {:?}",
+                                peer,
+                                connect_started.elapsed()
+                            );
+                        }
+                        report_buffers(SockRef::from(&stream));
+                    }
+
+                    // Started before the TLS handshake, which counts too.
+                    let deadline = start_deadline(
+                        exchange.deadline,
+                        &stream,
+                        head,
+                    )?;
+                    if proxy_protocol {
+                        send_proxy_header(&stream, verbose).map_err(
+                            |e| {
+                                LabeledError::new(
+                                "Failed to send PROXY protocol header",
+                            )
+                            .with_help(e.to_string())
+                            .with_label("here", head)
+                            },
+                        )?;
+                    }
+                    if let Some(starttls) = &starttls {
+                        starttls.upgrade(&stream, verbose).map_err(
+                            |e| {
+                                LabeledError::new("STARTTLS failed")
+                                    .with_help(
+                                        past_deadline(
+                                            deadline.as_ref(),
+                                            e,
+                                        )
+                                        .to_string(),
+                                    )
+                                    .with_label("here", head)
+                            },
+                        )?;
+                    }
+                    let connection = match tls_setup {
+                        Some((server_name, config)) => {
+                            let handshake_started = Instant::now();
+                            let early = match &payload {
+                                Payload::Bytes(bytes) if early_data => {
+                                    bytes.as_slice()
+                                }
+                                _ => &[],
+                            };
+                            let (stream, sent_early) =
+                                tls::TlsStream::connect(
+                                    stream,
+                                    server_name,
+                                    config,
+                                    early,
+                                )
+                                .map_err(|e| {
+                                    let e = past_deadline(
+                                        deadline.as_ref(),
+                                        e,
+                                    );
+                                    LabeledError::new(
+                                        "TLS handshake failed",
+                                    )
+                                    .with_help(e.to_string())
+                                    .with_label("here", head)
+                                })?;
+                            if verbose {
+                                eprintln!(
+                                    "* TLS handshake done in {:?}{}",
+                                    handshake_started.elapsed(),
+                                    if stream.resumed() {
+                                        ", session resumed"
+                                    } else {
+                                        ""
+                                    }
+                                );
+                                if early_data {
+                                    eprintln!(
+                                        "* {} bytes accepted as early data",
+                                        sent_early
+                                    );
+                                }
+                            }
+                            // What went out as early data is not sent
+                            // again.
+                            if let Payload::Bytes(bytes) = &mut payload
+                            {
+                                bytes.drain(..sent_early);
+                            }
+                            early_accepted = sent_early > 0;
+                            Connection::Tls(Box::new(stream))
+                        }
+                        None => Connection::Tcp(stream),
+                    };
+                    (connection, deadline)
+                }
+            };
+
+            let mut custom = connection_record(
+                connection.tcp().peer_addr().ok(),
+                connection.tcp().local_addr().ok(),
                 head,
-                signals,
-                ByteStreamType::Unknown,
             );
+            if let Connection::Tls(stream) = &connection {
+                custom.push(
+                    "alpn_protocol",
+                    stream
+                        .alpn_protocol()
+                        .map_or(Value::nothing(head), |protocol| {
+                            Value::string(protocol, head)
+                        }),
+                );
+                if session_cache {
+                    custom.push(
+                        "tls_resumed",
+                        Value::bool(stream.resumed(), head),
+                    );
+                }
+                if early_data {
+                    custom.push(
+                        "tls_early_data",
+                        Value::bool(early_accepted, head),
+                    );
+                }
+            }
+            match pool_key {
+                Some(key) => {
+                    custom
+                        .push("reused", Value::bool(was_reused, head));
+                    let checkout = Checkout::new(
+                        connection,
+                        pending.unwrap_or_default(),
+                        key,
+                        plugin.pool.clone(),
+                        exchange.complete.clone(),
+                        exchange.leftover.clone(),
+                    );
+                    send_and_stream(
+                        checkout, payload, &exchange, deadline, custom,
+                        engine, head,
+                    )
+                }
+                None => send_and_stream(
+                    connection, payload, &exchange, deadline, custom,
+                    engine, head,
+                ),
+            }
+        }
+    }
+}
+
+/// Errors if any of `flags` was given together with the `--mode` switch.
+fn reject_flags(
+    mode: &str,
+    flags: &[(&str, bool)],
+    head: Span,
+) -> Result<(), LabeledError> {
+    match flags.iter().find(|(_, set)| *set) {
+        Some((flag, _)) => {
+            Err(LabeledError::new("Invalid flag combination")
+                .with_help(format!(
+                    "--{} cannot be used with --{}.",
+                    flag, mode
+                ))
+                .with_label("here", head))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Errors if any of `flags` is set without the `--mode` they depend on.
+fn require_flags(
+    mode: &str,
+    flags: &[(&str, bool)],
+    head: Span,
+) -> Result<(), LabeledError> {
+    match flags.iter().find(|(_, set)| *set) {
+        Some((flag, _)) => {
+            Err(LabeledError::new("Invalid flag combination")
+                .with_help(format!(
+                    "--{} only applies together with --{}.",
+                    flag, mode
+                ))
+                .with_label("here", head))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Joins the multicast `group` on the interface of the local address the
+/// socket is bound to. IPv6 groups are joined on the default interface.
+fn join_multicast(
+    socket: &UdpSocket,
+    group: &str,
+    local: SocketAddr,
+) -> Result<(), String> {
+    let group: IpAddr = group.parse().map_err(|e| {
+        format!("`{}` is not an IP address: {}", group, e)
+    })?;
+    if !group.is_multicast() {
+        return Err(format!("{} is not a multicast address.", group));
+    }
+    match (group, local.ip()) {
+        (IpAddr::V4(group), IpAddr::V4(interface)) => socket
+            .join_multicast_v4(&group, &interface)
+            .map_err(|e| e.to_string()),
+        (IpAddr::V6(group), IpAddr::V6(_)) => socket
+            .join_multicast_v6(&group, 0)
+            .map_err(|e| e.to_string()),
+        (group, _) => Err(format!(
+            "Cannot join {} from the {} socket bound to {}. The group must be of the same IP version as the destination.",
+            group,
+            if local.is_ipv4() { "IPv4" } else { "IPv6" },
+            local
+        )),
+    }
+}
+
+/// Receives datagrams until `window` has passed since the first call,
+/// or until `count` of them have arrived, returning each with its sender.
+/// Running out of time after at least one datagram is the normal way to
+/// finish. Ctrl+C also ends the wait, with whatever arrived until then.
+fn collect_datagrams(
+    socket: &UdpSocket,
+    window: Duration,
+    count: Option<usize>,
+    signals: &Signals,
+) -> io::Result<Vec<(SocketAddr, Vec<u8>)>> {
+    let deadline = Instant::now() + window;
+    let mut buffer = vec![0u8; 65535];
+    let mut replies = Vec::new();
+    while count.is_none_or(|count| replies.len() < count) {
+        let remaining =
+            deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        match recv_interruptible(
+            socket,
+            &mut buffer,
+            remaining,
+            signals,
+        ) {
+            Ok((bytes_read, source_addr)) => {
+                replies
+                    .push((source_addr, buffer[..bytes_read].to_vec()));
+            }
+            // What arrived before Ctrl+C is kept, even if nothing did.
+            Err(_) if signals.interrupted() => return Ok(replies),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if replies.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no datagram arrived before the read timeout",
+        ));
+    }
+    Ok(replies)
+}
 
-            let metadata = Some(PipelineMetadata {
-                data_source: DataSource::None,
-                content_type: None,
-                custom: Record::new(),
+/// Waits up to `timeout` for a datagram, in slices of `INTERRUPT_POLL`
+/// so Ctrl+C ends the wait promptly, with an `Interrupted` error.
+fn recv_interruptible(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+    timeout: Duration,
+    signals: &Signals,
+) -> io::Result<(usize, SocketAddr)> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining =
+            deadline.saturating_duration_since(Instant::now());
+        socket.set_read_timeout(Some(
+            remaining.min(INTERRUPT_POLL).max(Duration::from_millis(1)),
+        ))?;
+        match socket.recv_from(buffer) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if signals.interrupted() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "Interrupted while waiting for a datagram",
+                    ));
+                }
+                if Instant::now() >= deadline {
+                    return Err(e);
+                }
+            }
+            other => return other,
+        }
+    }
+}
+
+/// The socket operations `send_and_stream` needs beyond reading and
+/// writing.
+trait ClientStream {
+    /// Closes the sending half, leaving the reply readable.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()>;
+
+    /// A second handle to the connection, to send on from another
+    /// thread while this one reads (--interactive).
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Writer>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this connection cannot be written from a second thread",
+        ))
+    }
+}
+
+/// The sending side of a connection used by --interactive.
+trait Writer: Write + ClientStream + Send {}
+
+impl<T: Write + ClientStream + Send> Writer for T {}
+
+/// Sends `payload` from a thread of its own, so the reply can be
+/// streamed back while the input is still arriving. A failure to send
+/// is reported on stderr, as the reply may already be in the pipeline.
+fn send_in_background(
+    mut writer: Box<dyn Writer>,
+    payload: Payload,
+    shutdown_write: bool,
+    signals: Signals,
+) {
+    thread::spawn(move || {
+        let sent =
+            payload.write_to(&mut writer, &signals).and_then(|()| {
+                if shutdown_write {
+                    writer.shutdown_write()?;
+                }
+                Ok(())
             });
+        if let Err(e) = sent {
+            eprintln!("Warning: failed to send the input: {}", e);
+        }
+    });
+}
+
+impl ClientStream for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Writer>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+#[cfg(unix)]
+impl ClientStream for UnixStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Writer>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl<S: ClientStream> ClientStream for Throttled<S> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.get_mut().shutdown_write()
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Writer>> {
+        self.get_ref().try_clone_writer()
+    }
+}
+
+impl ClientStream for tls::TlsStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        tls::TlsStream::shutdown_write(self)
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        tls::TlsStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl ClientStream for Connection {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown_write(),
+            Connection::Tls(stream) => stream.shutdown_write(),
+        }
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    fn try_clone_writer(&self) -> io::Result<Box<dyn Writer>> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone_writer(),
+            Connection::Tls(stream) => stream.try_clone_writer(),
+        }
+    }
+}
+
+impl ClientStream for Checkout {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.get_mut().shutdown_write()
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+}
+
+/// The largest UDP payload that fits in an IPv4 or IPv6 packet, without
+/// IPv6 jumbograms.
+const MAX_DATAGRAM_V4: usize = 65_507;
+const MAX_DATAGRAM_V6: usize = 65_527;
+
+/// The largest UDP payload that fits in a 1500-byte Ethernet frame
+/// without being fragmented.
+const UNFRAGMENTED_V4: usize = 1_472;
+const UNFRAGMENTED_V6: usize = 1_452;
+
+/// How often a waiting read checks for Ctrl+C.
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// Waits for reply data in short slices, so Ctrl+C ends the wait
+/// promptly. The socket's own read timeout must be set to
+/// `INTERRUPT_POLL`; `idle_timeout` is how long to wait in total.
+struct Interruptible<R> {
+    inner: R,
+    signals: Signals,
+    idle_timeout: Duration,
+    /// Kept alive as long as the reply is being read.
+    deadline: Option<Deadline>,
+}
+
+impl<R: Read> Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read_slices(buf) {
+            // A connection shut down by the deadline reads as
+            // end-of-file, which must not pass for a complete reply.
+            Ok(0) if !buf.is_empty() => match &self.deadline {
+                Some(deadline) if deadline.expired() => {
+                    Err(deadline.error("--deadline"))
+                }
+                _ => Ok(0),
+            },
+            Err(e) => Err(past_deadline(self.deadline.as_ref(), e)),
+            other => other,
+        }
+    }
+}
+
+impl<R: Read> Interruptible<R> {
+    fn read_slices(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            match self.inner.read(buf) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.signals.interrupted() {
+                        return Err(io::Error::other(
+                            "Interrupted while waiting for the reply",
+                        ));
+                    }
+                    if started.elapsed() >= self.idle_timeout {
+                        return Err(e);
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+/// Replaces an error caused by the deadline shutting the connection down
+/// with one that says so.
+fn past_deadline(
+    deadline: Option<&Deadline>,
+    e: io::Error,
+) -> io::Error {
+    match deadline {
+        Some(deadline) if deadline.expired() => {
+            deadline.error("--deadline")
+        }
+        _ => e,
+    }
+}
+
+/// Starts the --deadline watchdog for a TCP connection, if one was asked
+/// for.
+fn start_deadline(
+    limit: Option<Duration>,
+    stream: &TcpStream,
+    head: Span,
+) -> Result<Option<Deadline>, LabeledError> {
+    let Some(limit) = limit else {
+        return Ok(None);
+    };
+    let socket = stream.try_clone().map_err(|e| {
+        LabeledError::new("Failed to set up --deadline")
+            .with_help(e.to_string())
+            .with_label("here", head)
+    })?;
+    Ok(Some(Deadline::start(limit, move || {
+        let _ = socket.shutdown(Shutdown::Both);
+    })))
+}
+
+/// Options for the exchange of data over an open connection.
+#[derive(Clone)]
+struct Exchange {
+    /// How long to wait for the next piece of the reply.
+    read_timeout: Duration,
+    /// How long the whole exchange may take, counted from connecting.
+    deadline: Option<Duration>,
+    /// Close the sending half once the payload is out.
+    shutdown_write: bool,
+    /// Send the payload from its own thread while the reply is read.
+    interactive: bool,
+    /// End the reply after this delimiter.
+    read_until: Option<Vec<u8>>,
+    /// Frame the request and reply with a length header this wide.
+    length_prefix: Option<usize>,
+    /// Read every frame of the reply, not just the first.
+    split_frames: bool,
+    /// Fail on an incomplete last frame instead of dropping it.
+    strict: bool,
+    /// Return only the first line of the reply.
+    first_line: bool,
+    /// Return the reply as a hex dump instead of bytes.
+    hex_output: bool,
+    /// Parse the reply as JSON, falling back to its bytes.
+    from_json: bool,
+    /// Bytes per second allowed in each direction.
+    max_rate: Option<u64>,
+    max_size: Option<MaxSize>,
+    /// Declared as the reply's content type in its metadata.
+    content_type: Option<String>,
+    /// Save the reply here instead of returning it.
+    output_file: Option<Spanned<PathBuf>>,
+    /// Send the payload several times.
+    repeat: Option<Repeat>,
+    /// Set once the reply has been read up to its end, which lets a
+    /// --reuse connection go back into the pool.
+    complete: Arc<AtomicBool>,
+    /// What was read past the end of the reply, for the next reply on a
+    /// --reuse connection.
+    leftover: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Exchange {
+    /// The metadata attached to the reply, with the connection record
+    /// in `custom`.
+    fn reply_metadata(
+        &self,
+        custom: Record,
+    ) -> Option<PipelineMetadata> {
+        Some(PipelineMetadata {
+            data_source: DataSource::None,
+            content_type: self.content_type.clone(),
+            custom,
+        })
+    }
+
+    /// The output for a reply received in one piece: its bytes, with
+    /// --hex-output a dump of them, or with --from-json the value they
+    /// encode.
+    fn reply_value(&self, reply: Vec<u8>, head: Span) -> Value {
+        if self.hex_output {
+            return Value::string(hex_dump(&reply), head);
+        }
+        if self.from_json {
+            match serde_json::from_slice(&reply) {
+                Ok(parsed) => return json::from_json(parsed, head),
+                Err(e) => eprintln!(
+                    "Warning: the reply is not valid JSON ({}); returning it as binary.",
+                    e
+                ),
+            }
+        }
+        Value::binary(reply, head)
+    }
+
+    /// Applies `--max-size` to a reply that was received in one piece.
+    fn limit_reply(
+        &self,
+        mut reply: Vec<u8>,
+        head: Span,
+    ) -> Result<Vec<u8>, LabeledError> {
+        match self.max_size {
+            Some(max) if reply.len() as u64 > max.bytes => {
+                if !max.truncate {
+                    return Err(reply_too_large(max.bytes, head));
+                }
+                warn_truncated(max.bytes);
+                reply.truncate(max.bytes as usize);
+                Ok(reply)
+            }
+            _ => Ok(reply),
+        }
+    }
+}
+
+/// How often --repeat sends the payload.
+#[derive(Clone, Copy)]
+struct Repeat {
+    times: u64,
+    /// The pause before each send after the first.
+    delay: Duration,
+}
+
+/// The limit set by --max-size.
+#[derive(Clone, Copy)]
+struct MaxSize {
+    bytes: u64,
+    /// End the reply at the limit instead of failing.
+    truncate: bool,
+}
+
+fn reply_too_large(limit: u64, head: Span) -> LabeledError {
+    LabeledError::new("Reply too large")
+        .with_help(format!(
+            "The remote host sent more than the {} bytes allowed by --max-size.",
+            limit
+        ))
+        .with_label("here", head)
+}
+
+fn warn_truncated(limit: u64) {
+    eprintln!(
+        "Warning: the reply was cut off at {} bytes (--max-size).",
+        limit
+    );
+}
+
+/// Stops a streamed reply at the --max-size limit. Going past it is an
+/// error, or with --truncate the end of the stream.
+struct SizeLimited<R> {
+    inner: R,
+    max: MaxSize,
+    read: u64,
+    /// Set once the remote host has sent more than the limit.
+    over: bool,
+}
+
+impl<R: Read> Read for SizeLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.max.bytes - self.read;
+        if self.over && !self.max.truncate {
+            return Err(io::Error::other(format!(
+                "Reply too large: the remote host sent more than the {} bytes allowed by --max-size.",
+                self.max.bytes
+            )));
+        }
+        // With --truncate the limit is the end of the reply, so there is
+        // no waiting on the remote host to see whether more would come.
+        if self.over
+            || buf.is_empty()
+            || (remaining == 0 && self.max.truncate)
+        {
+            return Ok(0);
+        }
+        // One byte past the limit is asked for, so a reply that goes on
+        // shows up in the same read. Only a reply that actually does
+        // counts as too large.
+        let len = buf.len().min(
+            usize::try_from(remaining)
+                .unwrap_or(usize::MAX)
+                .saturating_add(1),
+        );
+        let n = self.inner.read(&mut buf[..len])?;
+        if n as u64 > remaining {
+            self.over = true;
+            self.read = self.max.bytes;
+            if self.max.truncate {
+                warn_truncated(self.max.bytes);
+            }
+            // What came before the limit is passed on first.
+            return match remaining {
+                0 => self.read(buf),
+                _ => Ok(remaining as usize),
+            };
+        }
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Ends a reply right after the first occurrence of a delimiter, even if
+/// the server keeps the connection open. Anything the server sent after
+/// the delimiter in the same read is kept in `leftover` for the next
+/// reply.
+struct UntilDelimiter<R> {
+    inner: R,
+    delimiter: Vec<u8>,
+    /// The last bytes passed on, to find a delimiter split across reads.
+    tail: Vec<u8>,
+    done: bool,
+    /// Set once the delimiter is found.
+    complete: Arc<AtomicBool>,
+    leftover: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<R> UntilDelimiter<R> {
+    fn new(inner: R, delimiter: Vec<u8>, exchange: &Exchange) -> Self {
+        UntilDelimiter {
+            inner,
+            delimiter,
+            tail: Vec::new(),
+            done: false,
+            complete: exchange.complete.clone(),
+            leftover: exchange.leftover.clone(),
+        }
+    }
+}
+
+impl<R: Read> Read for UntilDelimiter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        let n = self.inner.read(buf)?;
+        if n == 0 {
+            return Ok(0);
+        }
+        // Search the end of the previous read together with this one.
+        let kept = self.tail.len();
+        self.tail.extend_from_slice(&buf[..n]);
+        let found = self
+            .tail
+            .windows(self.delimiter.len())
+            .position(|window| window == self.delimiter.as_slice());
+        if let Some(start) = found {
+            self.done = true;
+            let end = start + self.delimiter.len() - kept;
+            self.leftover
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend_from_slice(&buf[end..n]);
+            self.complete.store(true, Ordering::Relaxed);
+            return Ok(end);
+        }
+        let keep = self.tail.len().min(self.delimiter.len() - 1);
+        self.tail.drain(..self.tail.len() - keep);
+        Ok(n)
+    }
+}
+
+/// Sends the payload over an established connection and streams the reply.
+fn send_and_stream<S: Read + Write + ClientStream + Send + 'static>(
+    stream: S,
+    payload: Payload,
+    exchange: &Exchange,
+    deadline: Option<Deadline>,
+    custom: Record,
+    engine: &EngineInterface,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    let mut stream = Throttled::new(stream, exchange.max_rate);
+    let framed = exchange.length_prefix.is_some()
+        || exchange.read_until.is_some();
+    if matches!(payload, Payload::Messages(_))
+        || (exchange.repeat.is_some() && framed)
+    {
+        return exchange_rounds(
+            stream, payload, exchange, deadline, custom, engine, head,
+        );
+    }
+    if exchange.interactive {
+        let writer =
+            stream.get_ref().try_clone_writer().map_err(|e| {
+                LabeledError::new("Failed to set up --interactive")
+                    .with_help(e.to_string())
+                    .with_label("here", head)
+            })?;
+        send_in_background(
+            writer,
+            payload,
+            exchange.shutdown_write,
+            engine.signals().clone(),
+        );
+    } else {
+        let written = match (exchange.length_prefix, exchange.repeat) {
+            (Some(width), _) => {
+                payload.into_bytes().and_then(|bytes| {
+                    framing::write_frame(&mut stream, &bytes, width)?;
+                    stream.flush()
+                })
+            }
+            (None, Some(repeat)) => {
+                payload.into_bytes().and_then(|bytes| {
+                    send_repeatedly(
+                        &mut stream,
+                        &bytes,
+                        repeat,
+                        engine.signals(),
+                    )
+                })
+            }
+            (None, None) => {
+                payload.write_to(&mut stream, engine.signals())
+            }
+        };
+        written.map_err(|e| {
+            LabeledError::new("Failed to write to socket")
+                .with_help(
+                    past_deadline(deadline.as_ref(), e).to_string(),
+                )
+                .with_label("here", head)
+        })?;
+        if exchange.shutdown_write {
+            stream.shutdown_write().map_err(|e| {
+                LabeledError::new(
+                    "Failed to shut down the sending side",
+                )
+                .with_help(
+                    past_deadline(deadline.as_ref(), e).to_string(),
+                )
+                .with_label("here", head)
+            })?;
+        }
+    }
+
+    stream.set_read_timeout(Some(INTERRUPT_POLL)).map_err(|e| {
+        LabeledError::new("Failed to set read timeout")
+            .with_help(e.to_string())
+            .with_label("here", head)
+    })?;
+    let stream = Interruptible {
+        inner: stream,
+        signals: engine.signals().clone(),
+        idle_timeout: exchange.read_timeout,
+        deadline,
+    };
+    if let Some(width) = exchange.length_prefix {
+        if exchange.split_frames {
+            let frames = Frames {
+                reader: stream,
+                width,
+                exchange: exchange.clone(),
+                done: false,
+                head,
+            };
+            return Ok(PipelineData::ListStream(
+                ListStream::new(frames, head, engine.signals().clone()),
+                exchange.reply_metadata(custom),
+            ));
+        }
+        return read_framed_reply(
+            stream, width, exchange, custom, head,
+        );
+    }
+    if exchange.first_line {
+        return read_first_line(stream, exchange, custom, head);
+    }
+    let mut reader: Box<dyn Read + Send> = match &exchange.read_until {
+        Some(delimiter) => Box::new(UntilDelimiter::new(
+            stream,
+            delimiter.clone(),
+            exchange,
+        )),
+        None => Box::new(stream),
+    };
+    if let Some(max) = exchange.max_size {
+        reader = Box::new(SizeLimited {
+            inner: reader,
+            max,
+            read: 0,
+            over: false,
+        });
+    }
+    if let Some(path) = &exchange.output_file {
+        return save_reply(reader, path, exchange, custom, head);
+    }
+    if exchange.hex_output {
+        let mut reply = Vec::new();
+        reader.read_to_end(&mut reply).map_err(|e| {
+            LabeledError::new("Failed to read from socket")
+                .with_help(e.to_string())
+                .with_label("here", head)
+        })?;
+        return Ok(PipelineData::Value(
+            exchange.reply_value(reply, head),
+            exchange.reply_metadata(custom),
+        ));
+    }
+    Ok(stream_reply(
+        reader,
+        exchange.reply_metadata(custom),
+        engine,
+        head,
+    ))
+}
+
+/// Sends `bytes` as often as `repeat` says, one copy right after the
+/// other unless it asks for a pause. Ctrl+C stops the sending.
+fn send_repeatedly(
+    writer: &mut impl Write,
+    bytes: &[u8],
+    repeat: Repeat,
+    signals: &Signals,
+) -> io::Result<()> {
+    for round in 0..repeat.times {
+        if round > 0 && !repeat.delay.is_zero() {
+            writer.flush()?;
+            pause(repeat.delay, signals);
+        }
+        if signals.interrupted() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Interrupted while sending",
+            ));
+        }
+        writer.write_all(bytes)?;
+    }
+    writer.flush()
+}
+
+/// Sleeps for `delay` in short steps, so Ctrl+C cuts it short.
+fn pause(delay: Duration, signals: &Signals) {
+    let resume = Instant::now() + delay;
+    while !signals.interrupted() {
+        let left = resume.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(INTERRUPT_POLL));
+    }
+}
+
+/// Sends the items of a list input one by one, or the payload once per
+/// --repeat, reading a reply after each send, over the same connection.
+/// The replies are returned as a list, each as soon as it is in.
+fn exchange_rounds<S: Read + Write + ClientStream + Send + 'static>(
+    stream: Throttled<S>,
+    payload: Payload,
+    exchange: &Exchange,
+    deadline: Option<Deadline>,
+    custom: Record,
+    engine: &EngineInterface,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    let messages = match payload {
+        Payload::Messages(messages) => messages,
+        payload => vec![payload.into_bytes().map_err(|e| {
+            LabeledError::new("Failed to read the input")
+                .with_help(e.to_string())
+                .with_label("here", head)
+        })?],
+    };
+    stream.set_read_timeout(Some(INTERRUPT_POLL)).map_err(|e| {
+        LabeledError::new("Failed to set read timeout")
+            .with_help(e.to_string())
+            .with_label("here", head)
+    })?;
+    let rounds = Rounds::new(
+        stream,
+        messages,
+        exchange,
+        deadline,
+        engine.signals().clone(),
+        head,
+    );
+    Ok(PipelineData::ListStream(
+        ListStream::new(rounds, head, engine.signals().clone()),
+        exchange.reply_metadata(custom),
+    ))
+}
+
+/// Yields the reply to each message sent, and stops after the first
+/// error.
+struct Rounds<S> {
+    stream: Interruptible<Throttled<S>>,
+    /// Sent in turn, starting over until `rounds` sends are made.
+    messages: Vec<Vec<u8>>,
+    rounds: u64,
+    /// The pause before each send after the first.
+    delay: Duration,
+    exchange: Exchange,
+    /// What arrived after the last --read-until delimiter, which belongs
+    /// to the next reply.
+    buffered: Vec<u8>,
+    round: u64,
+    failed: bool,
+    head: Span,
+}
+
+impl<S: Read + Write + ClientStream> Iterator for Rounds<S> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.failed || self.round >= self.rounds {
+            return None;
+        }
+        if self.round > 0 {
+            pause(self.delay, &self.stream.signals);
+        }
+        if self.stream.signals.interrupted() {
+            return None;
+        }
+        self.round += 1;
+        match self.exchange_once() {
+            Ok(reply) => {
+                Some(self.exchange.reply_value(reply, self.head))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Value::error(e.into(), self.head))
+            }
+        }
+    }
+}
+
+impl<S: Read + Write + ClientStream> Rounds<S> {
+    /// Each reply ends as --read-until or --length-prefixed in
+    /// `exchange` say, or without either once the server stays silent for
+    /// the read timeout.
+    fn new(
+        stream: Throttled<S>,
+        messages: Vec<Vec<u8>>,
+        exchange: &Exchange,
+        deadline: Option<Deadline>,
+        signals: Signals,
+        head: Span,
+    ) -> Self {
+        let repeat = exchange.repeat.unwrap_or(Repeat {
+            times: 1,
+            delay: Duration::ZERO,
+        });
+        Rounds {
+            stream: Interruptible {
+                inner: stream,
+                signals,
+                idle_timeout: exchange.read_timeout,
+                deadline,
+            },
+            rounds: messages.len() as u64 * repeat.times,
+            messages,
+            delay: repeat.delay,
+            exchange: exchange.clone(),
+            buffered: Vec::new(),
+            round: 0,
+            failed: false,
+            head,
+        }
+    }
+
+    fn exchange_once(&mut self) -> Result<Vec<u8>, LabeledError> {
+        let head = self.head;
+        let last = self.round == self.rounds;
+        let message = &self.messages
+            [((self.round - 1) % self.messages.len() as u64) as usize];
+        let writer = &mut self.stream.inner;
+        let mut sent = match self.exchange.length_prefix {
+            Some(width) => {
+                framing::write_frame(writer, message, width).map(drop)
+            }
+            None => writer.write_all(message),
+        }
+        .and_then(|()| writer.flush());
+        // The sending half can only be closed once nothing more is sent.
+        if last && self.exchange.shutdown_write {
+            sent = sent.and_then(|()| writer.shutdown_write());
+        }
+        sent.map_err(|e| {
+            LabeledError::new("Failed to write to socket")
+                .with_help(
+                    past_deadline(self.stream.deadline.as_ref(), e)
+                        .to_string(),
+                )
+                .with_label("here", head)
+        })?;
+
+        let limit = self.exchange.max_size.map_or(usize::MAX, |max| {
+            usize::try_from(max.bytes).unwrap_or(usize::MAX)
+        });
+        let reply = match (
+            self.exchange.length_prefix,
+            &self.exchange.read_until,
+        ) {
+            (Some(width), _) => {
+                framing::read_frame(&mut self.stream, width, limit)
+            }
+            (None, Some(delimiter)) => {
+                let delimiter = delimiter.clone();
+                self.read_delimited(&delimiter, limit)
+            }
+            (None, None) => self.read_until_idle(limit),
+        };
+        match reply {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) => Err(LabeledError::new("No reply")
+                .with_help(format!(
+                    "The server closed the connection before replying to send {} of {}.",
+                    self.round, self.rounds
+                ))
+                .with_label("here", head)),
+            Err(e) => Err(LabeledError::new("Failed to read from socket")
+                .with_help(e.to_string())
+                .with_label("here", head)),
+        }
+    }
+
+    /// Reads until the server stays silent for the read timeout, for
+    /// replies that have nothing else to mark their end. `None` if the
+    /// server closes the connection without sending anything.
+    fn read_until_idle(
+        &mut self,
+        limit: usize,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut reply = std::mem::take(&mut self.buffered);
+        let mut chunk = [0u8; 8192];
+        loop {
+            if reply.len() > limit {
+                return Err(too_long(limit));
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) if reply.is_empty() => return Ok(None),
+                Ok(0) => return Ok(Some(reply)),
+                Ok(n) => reply.extend_from_slice(&chunk[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(Some(reply))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads up to and including the next `delimiter`. Whatever arrives
+    /// past it is kept for the next round. `None` if the server closes
+    /// the connection first.
+    fn read_delimited(
+        &mut self,
+        delimiter: &[u8],
+        limit: usize,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut searched = 0;
+        let mut chunk = [0u8; 8192];
+        loop {
+            let found = self.buffered[searched..]
+                .windows(delimiter.len())
+                .position(|window| window == delimiter);
+            if let Some(start) = found {
+                let end = searched + start + delimiter.len();
+                let rest = self.buffered.split_off(end);
+                let reply = std::mem::replace(&mut self.buffered, rest);
+                return if reply.len() > limit {
+                    Err(too_long(limit))
+                } else {
+                    Ok(Some(reply))
+                };
+            }
+            if self.buffered.len() > limit {
+                return Err(too_long(limit));
+            }
+            // A delimiter split across reads starts in the last bytes.
+            searched =
+                self.buffered.len().saturating_sub(delimiter.len() - 1);
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.buffered.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn too_long(limit: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "the reply is longer than the limit of {} bytes set by --max-size",
+            limit
+        ),
+    )
+}
+
+/// Yields the payload of each frame of a reply with
+/// `--output-split-frames`, until the connection closes or stays silent
+/// for the read timeout between two frames.
+struct Frames<R> {
+    reader: R,
+    width: usize,
+    exchange: Exchange,
+    done: bool,
+    head: Span,
+}
+
+impl<R: Read> Iterator for Frames<R> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.done {
+            return None;
+        }
+        let limit = self.exchange.max_size.map_or(usize::MAX, |max| {
+            usize::try_from(max.bytes).unwrap_or(usize::MAX)
+        });
+        // The first byte tells a reply that ended from a frame that was
+        // cut short.
+        let mut first = [0u8; 1];
+        let frame = match self.reader.read(&mut first) {
+            Ok(0) => Ok(None),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+            Ok(_) => {
+                let mut reader = (&first[..]).chain(&mut self.reader);
+                match framing::read_frame(
+                    &mut reader,
+                    self.width,
+                    limit,
+                ) {
+                    Err(e)
+                        if !self.exchange.strict
+                            && matches!(
+                                e.kind(),
+                                io::ErrorKind::UnexpectedEof
+                                    | io::ErrorKind::WouldBlock
+                                    | io::ErrorKind::TimedOut
+                            ) =>
+                    {
+                        eprintln!(
+                            "Warning: dropped an incomplete last frame ({}).",
+                            e
+                        );
+                        Ok(None)
+                    }
+                    frame => frame,
+                }
+            }
+        };
+        match frame {
+            Ok(Some(payload)) => {
+                Some(self.exchange.reply_value(payload, self.head))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                let error = LabeledError::new("Failed to read a frame")
+                    .with_help(e.to_string())
+                    .with_label("here", self.head);
+                Some(Value::error(error.into(), self.head))
+            }
+        }
+    }
+}
+
+/// Reads a reply framed by a length header and returns its payload. A
+/// declared length over --max-size is refused without reading it.
+fn read_framed_reply(
+    mut reader: impl Read,
+    width: usize,
+    exchange: &Exchange,
+    custom: Record,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    let limit = exchange.max_size.map_or(usize::MAX, |max| {
+        usize::try_from(max.bytes).unwrap_or(usize::MAX)
+    });
+    let reply = framing::read_frame(&mut reader, width, limit)
+        .map_err(|e| {
+            LabeledError::new("Failed to read the framed reply")
+                .with_help(e.to_string())
+                .with_label("here", head)
+        })?
+        .ok_or_else(|| {
+            LabeledError::new("No reply")
+                .with_help(
+                    "The server closed the connection without sending a frame.",
+                )
+                .with_label("here", head)
+        })?;
+    exchange.complete.store(true, Ordering::Relaxed);
+    if let Some(path) = &exchange.output_file {
+        return save_reply(
+            reply.as_slice(),
+            path,
+            exchange,
+            custom,
+            head,
+        );
+    }
+    Ok(PipelineData::Value(
+        exchange.reply_value(reply, head),
+        exchange.reply_metadata(custom),
+    ))
+}
+
+/// Reads the reply up to the first newline and returns that line, without
+/// its line ending. Whatever follows is left unread when the connection
+/// is closed.
+fn read_first_line(
+    reader: impl Read,
+    exchange: &Exchange,
+    custom: Record,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    let limit = exchange.max_size.map_or(u64::MAX, |max| max.bytes);
+    let mut line = Vec::new();
+    let mut reader = BufReader::new(reader.take(limit));
+    reader.read_until(b'\n', &mut line).map_err(|e| {
+        LabeledError::new("Failed to read from socket")
+            .with_help(e.to_string())
+            .with_label("here", head)
+    })?;
+    // Anything read past the line is kept for the next reply.
+    if line.ends_with(b"\n") {
+        exchange
+            .leftover
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend_from_slice(reader.buffer());
+        exchange.complete.store(true, Ordering::Relaxed);
+    }
+    if !line.ends_with(b"\n") && line.len() as u64 == limit {
+        match exchange.max_size {
+            Some(max) if max.truncate => warn_truncated(max.bytes),
+            _ => return Err(reply_too_large(limit, head)),
+        }
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    Ok(PipelineData::Value(
+        Value::string(String::from_utf8_lossy(&line), head),
+        exchange.reply_metadata(custom),
+    ))
+}
+
+/// Copies the reply into the --output-file as it arrives and returns a
+/// summary of what was written.
+fn save_reply(
+    mut reader: impl Read,
+    path: &Spanned<PathBuf>,
+    exchange: &Exchange,
+    custom: Record,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    let mut file = File::create(&path.item).map_err(|e| {
+        LabeledError::new("Failed to create file")
+            .with_help(format!("{}: {}", path.item.display(), e))
+            .with_label("here", path.span)
+    })?;
+    let written = io::copy(&mut reader, &mut file).map_err(|e| {
+        LabeledError::new("Failed to save the reply")
+            .with_help(e.to_string())
+            .with_label("here", head)
+    })?;
+    Ok(PipelineData::Value(
+        Value::record(
+            record! {
+                "bytes_written" => Value::int(
+                    written.min(i64::MAX as u64) as i64,
+                    head,
+                ),
+                "path" => Value::string(
+                    path.item.to_string_lossy(),
+                    head,
+                ),
+            },
+            head,
+        ),
+        exchange.reply_metadata(custom),
+    ))
+}
+
+/// Wraps the reading half of a connection in a `ByteStream`, so the reply
+/// is passed on as it arrives.
+fn stream_reply(
+    reader: Box<dyn Read + Send>,
+    metadata: Option<PipelineMetadata>,
+    engine: &EngineInterface,
+    head: Span,
+) -> PipelineData {
+    let source = ByteStreamSource::Read(reader);
+    let signals = engine.signals().clone();
+    let byte_stream =
+        ByteStream::new(source, head, signals, ByteStreamType::Unknown);
+
+    PipelineData::ByteStream(byte_stream, metadata)
+}
+
+/// Describes the two ends of a connection, in the same shape as the
+/// record `socket listen` passes to its closure.
+fn connection_record(
+    remote: Option<SocketAddr>,
+    local: Option<SocketAddr>,
+    span: Span,
+) -> Record {
+    record! {
+        "remote_addr" => remote.map_or(Value::nothing(span), |addr| {
+            Value::string(addr.ip().to_string(), span)
+        }),
+        "remote_port" => remote.map_or(Value::nothing(span), |addr| {
+            Value::int(addr.port().into(), span)
+        }),
+        "local_addr" => local.map_or(Value::nothing(span), |addr| {
+            Value::string(addr.to_string(), span)
+        }),
+    }
+}
+
+/// Sends the payload over a Unix domain socket and streams the reply.
+#[cfg(unix)]
+fn connect_unix(
+    path: &str,
+    payload: Payload,
+    exchange: &Exchange,
+    read_timeout: Duration,
+    engine: &EngineInterface,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    let stream = UnixStream::connect(path).map_err(|e| {
+        LabeledError::new("Failed to connect to Unix socket")
+            .with_help(format!("{}: {}", path, e))
+            .with_label("here", head)
+    })?;
+    stream.set_read_timeout(Some(read_timeout)).map_err(|e| {
+        LabeledError::new("Failed to set read timeout")
+            .with_help(e.to_string())
+            .with_label("here", head)
+    })?;
+
+    let deadline = match exchange.deadline {
+        Some(limit) => {
+            let socket = stream.try_clone().map_err(|e| {
+                LabeledError::new("Failed to set up --deadline")
+                    .with_help(e.to_string())
+                    .with_label("here", head)
+            })?;
+            Some(Deadline::start(limit, move || {
+                let _ = socket.shutdown(Shutdown::Both);
+            }))
+        }
+        None => None,
+    };
+
+    let custom = record! {
+        "remote_addr" => Value::string(path, head),
+        "remote_port" => Value::nothing(head),
+        "local_addr" => Value::nothing(head),
+    };
+    send_and_stream(
+        stream, payload, exchange, deadline, custom, engine, head,
+    )
+}
+
+#[cfg(not(unix))]
+fn connect_unix(
+    _path: &str,
+    _payload: Payload,
+    _exchange: &Exchange,
+    _read_timeout: Duration,
+    _engine: &EngineInterface,
+    head: Span,
+) -> Result<PipelineData, LabeledError> {
+    Err(LabeledError::new("Unix domain sockets are not supported")
+        .with_help("--unix is only available on Unix-like systems.")
+        .with_label("here", head))
+}
+
+/// Parses a `--bind` value, which may be a bare IP or an `ip:port` pair.
+fn parse_bind_addr(bind: &str) -> io::Result<SocketAddr> {
+    if let Ok(ip) = bind.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 0));
+    }
+    bind.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No addresses found for {}", bind),
+        )
+    })
+}
+
+/// Says which local address could not be bound, and what to do if its
+/// port is taken.
+fn local_bind_error(local: SocketAddr, e: io::Error) -> io::Error {
+    let message = if e.kind() == io::ErrorKind::AddrInUse {
+        format!(
+            "local address {} is already in use. Pick another --local-port, or leave it out to let the system choose one.",
+            local
+        )
+    } else {
+        format!("cannot send from {}: {}", local, e)
+    };
+    io::Error::new(e.kind(), message)
+}
+
+/// The "any" address of the same family as `addr`, with an OS-chosen port.
+fn unspecified_addr(addr: &SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+/// Tries each address in turn, giving every attempt the full `timeout`,
+/// and returns the first connection that succeeds. If all fail, the
+/// error lists what went wrong with each address.
+fn connect_any(
+    addrs: &[SocketAddr],
+    options: &TcpOptions,
+    timeout: Duration,
+) -> Result<TcpStream, String> {
+    let mut failures = Vec::new();
+    for addr in addrs {
+        match connect_tcp(addr, options, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => failures.push((*addr, e)),
+        }
+    }
+    Err(describe_failures(&failures))
+}
+
+/// One line per failed address, saying whether it timed out, refused the
+/// connection or could not be reached. With more than one address the
+/// lines are bulleted, so a dual-stack or round-robin host shows which
+/// of its addresses are down.
+fn describe_failures(failures: &[(SocketAddr, io::Error)]) -> String {
+    let lines: Vec<String> = failures
+        .iter()
+        .map(|(addr, e)| {
+            let what = match e.kind() {
+                io::ErrorKind::TimedOut => "timed out",
+                io::ErrorKind::ConnectionRefused => "refused",
+                io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable => "unreachable",
+                _ => "failed",
+            };
+            format!("{}: {} ({})", addr, what, e)
+        })
+        .collect();
+    match lines.as_slice() {
+        [line] => line.clone(),
+        _ => format!(
+            "None of the {} addresses accepted the connection:\n{}",
+            lines.len(),
+            lines
+                .iter()
+                .map(|line| format!("- {}", line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    }
+}
+
+/// Socket settings that have to be in place before a TCP connection is
+/// made.
+struct TcpOptions {
+    /// The local address to connect from.
+    bind: Option<SocketAddr>,
+    /// The local port to connect from, on any address if `bind` is not
+    /// given. Already part of `bind` if it is.
+    local_port: Option<u16>,
+    ttl: Option<u32>,
+    /// When and how often keepalive probes are sent.
+    keepalive: Option<TcpKeepalive>,
+    buffers: BufferSizes,
+}
+
+/// Socket buffer sizes requested with --recv-buffer and --send-buffer.
+#[derive(Clone, Copy)]
+struct BufferSizes {
+    recv: Option<usize>,
+    send: Option<usize>,
+}
+
+impl BufferSizes {
+    fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(size) = self.recv {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the buffer sizes in effect, which the system may have rounded
+/// or, as Linux does, doubled to make room for its own bookkeeping.
+fn report_buffers(socket: SockRef<'_>) {
+    if let (Ok(recv), Ok(send)) =
+        (socket.recv_buffer_size(), socket.send_buffer_size())
+    {
+        eprintln!(
+            "* Socket buffers: {} bytes receive, {} bytes send",
+            recv, send
+        );
+    }
+}
+
+fn buffer_size(
+    size: Option<Spanned<i64>>,
+) -> Result<Option<usize>, LabeledError> {
+    match size {
+        Some(size) if size.item < 1 => {
+            Err(LabeledError::new("Invalid buffer size")
+                .with_help(
+                    "Socket buffer sizes must be at least 1 byte.",
+                )
+                .with_label("here", size.span))
+        }
+        Some(size) => {
+            Ok(Some(usize::try_from(size.item).unwrap_or(usize::MAX)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Sends the PROXY protocol header naming the two ends of `stream`. It
+/// goes first, ahead of any TLS handshake.
+fn send_proxy_header(
+    mut stream: &TcpStream,
+    verbose: bool,
+) -> io::Result<()> {
+    let header = proxy_protocol::header_v1(
+        stream.local_addr()?,
+        stream.peer_addr()?,
+    );
+    if verbose {
+        eprintln!("* Sending {}", header.trim_end());
+    }
+    stream.write_all(header.as_bytes())
+}
+
+/// Opens a TCP connection with the given options.
+fn connect_tcp(
+    addr: &SocketAddr,
+    options: &TcpOptions,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if let Some(ttl) = options.ttl {
+        set_ttl(SockRef::from(&socket), ttl, *addr)?;
+    }
+    if let Some(keepalive) = &options.keepalive {
+        socket.set_tcp_keepalive(keepalive)?;
+    }
+    // Set before connecting, so the receive buffer is reflected in the
+    // window scaling agreed on in the handshake.
+    options.buffers.apply(SockRef::from(&socket))?;
+    let local = match (options.bind, options.local_port) {
+        (Some(local), _) => Some(local),
+        (None, Some(port)) => {
+            Some(SocketAddr::new(unspecified_addr(addr).ip(), port))
+        }
+        (None, None) => None,
+    };
+    if let Some(local) = local {
+        // Lets a fixed local port be used again while the previous
+        // connection from it is still in TIME_WAIT.
+        if options.local_port.is_some() {
+            socket.set_reuse_address(true)?;
+        }
+        socket
+            .bind(&local.into())
+            .map_err(|e| local_bind_error(local, e))?;
+    }
+    socket.connect_timeout(&(*addr).into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Adds the time between keepalive probes and how many go unanswered
+/// before the connection is dropped. Returns the flags for whichever of
+/// the two this platform cannot set, which are left out.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "tvos",
+    target_os = "watchos",
+    target_os = "windows"
+))]
+fn keepalive_probes(
+    mut keepalive: TcpKeepalive,
+    interval: Option<Duration>,
+    retries: Option<u32>,
+) -> (TcpKeepalive, Vec<&'static str>) {
+    if let Some(interval) = interval {
+        keepalive = keepalive.with_interval(interval);
+    }
+    if let Some(retries) = retries {
+        keepalive = keepalive.with_retries(retries);
+    }
+    (keepalive, Vec::new())
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "tvos",
+    target_os = "watchos",
+    target_os = "windows"
+)))]
+fn keepalive_probes(
+    keepalive: TcpKeepalive,
+    interval: Option<Duration>,
+    retries: Option<u32>,
+) -> (TcpKeepalive, Vec<&'static str>) {
+    let mut ignored = Vec::new();
+    if interval.is_some() {
+        ignored.push("keepalive-interval");
+    }
+    if retries.is_some() {
+        ignored.push("keepalive-retries");
+    }
+    (keepalive, ignored)
+}
+
+/// Sets the TTL, or the hop limit for IPv6, for packets sent to `dest`.
+/// Multicast traffic has its own setting, which is set too when `dest`
+/// is a group address.
+fn set_ttl(
+    socket: SockRef<'_>,
+    ttl: u32,
+    dest: SocketAddr,
+) -> io::Result<()> {
+    match dest.ip() {
+        IpAddr::V4(ip) => {
+            socket.set_ttl_v4(ttl)?;
+            if ip.is_multicast() {
+                socket.set_multicast_ttl_v4(ttl)?;
+            }
+        }
+        IpAddr::V6(ip) => {
+            socket.set_unicast_hops_v6(ttl)?;
+            if ip.is_multicast() {
+                socket.set_multicast_hops_v6(ttl)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The data to send to the remote host.
+enum Payload {
+    /// Input that was already a value in memory.
+    Bytes(Vec<u8>),
+    /// A byte stream input, copied to the socket as it is read.
+    Stream(Box<dyn Read + Send>),
+    /// Input sent a line at a time, with a pause after each line.
+    Lines(Vec<u8>, Duration),
+    /// A list input, each item sent as a message of its own with its
+    /// reply read before the next.
+    Messages(Vec<Vec<u8>>),
+}
+
+impl Payload {
+    /// Takes the data to send from the pipeline. With `crlf`, bare `\n`
+    /// line endings in string input are sent as `\r\n`. With
+    /// `line_delay`, string and binary input is sent a line at a time,
+    /// split after each `\n`, and with `encoding` string input is decoded
+    /// into the bytes it spells out; a byte stream is collected for
+    /// either. With `auto_json`, records and
+    /// lists are sent as a line of JSON. Otherwise, with `split_lists`,
+    /// each item of a list is a message of its own.
+    fn from_input(
+        input: PipelineData,
+        crlf: bool,
+        line_delay: Option<Duration>,
+        encoding: Option<InputEncoding>,
+        auto_json: bool,
+        split_lists: bool,
+        head: Span,
+    ) -> Result<Self, LabeledError> {
+        match input {
+            PipelineData::ByteStream(stream, ..)
+                if line_delay.is_none() && encoding.is_none() =>
+            {
+                return Ok(match stream.reader() {
+                    Some(reader) => Payload::Stream(Box::new(reader)),
+                    None => Payload::Bytes(vec![]),
+                });
+            }
+            _ => {}
+        }
+
+        let input_val = input.into_value(head)?;
+        if auto_json
+            && matches!(
+                input_val,
+                Value::Record { .. } | Value::List { .. }
+            )
+        {
+            let mut bytes = json::to_json(&input_val)
+                .and_then(|json| {
+                    serde_json::to_vec(&json).map_err(|e| e.to_string())
+                })
+                .map_err(|e| {
+                    LabeledError::new("Failed to convert input to JSON")
+                        .with_help(e)
+                        .with_label("input originates from here", head)
+                })?;
+            bytes.push(b'\n');
+            return Ok(Payload::Bytes(bytes));
+        }
+        let input_val = match input_val {
+            Value::List { vals, .. } if split_lists => {
+                return vals
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        message_bytes(item, index, crlf, encoding)
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Payload::Messages);
+            }
+            other => other,
+        };
+        match (input_val, encoding) {
+            (Value::Nothing { .. }, _) => Ok(Payload::Bytes(vec![])),
+            (Value::String { val, .. }, Some(encoding)) => {
+                encoding.decode(&val).map(Payload::Bytes).map_err(|e| {
+                    LabeledError::new("Invalid encoded input")
+                        .with_help(e)
+                        .with_label("input originates from here", head)
+                })
+            }
+            (other, Some(_)) => {
+                Err(LabeledError::new("Unsupported input type")
+                    .with_help(format!(
+                "--input-encoding decodes string input, but got {}",
+                other.get_type()
+            ))
+                    .with_label("input originates from here", head))
+            }
+            (Value::String { val, .. }, None) => {
+                let text = if crlf { to_crlf(&val) } else { val };
+                Ok(match line_delay {
+                    Some(delay) => {
+                        Payload::Lines(text.into_bytes(), delay)
+                    }
+                    None => Payload::Bytes(text.into_bytes()),
+                })
+            }
+            (Value::Binary { val, .. }, None) => Ok(match line_delay {
+                Some(delay) => Payload::Lines(val, delay),
+                None => Payload::Bytes(val),
+            }),
+            (other, None) => {
+                Err(LabeledError::new("Unsupported input type")
+                    .with_help(format!(
+                        "Expected string or binary, but got {}",
+                        other.get_type()
+                    ))
+                    .with_label("input originates from here", head))
+            }
+        }
+    }
+
+    fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Payload::Bytes(bytes) | Payload::Lines(bytes, _) => {
+                Ok(bytes)
+            }
+            Payload::Messages(messages) => Ok(messages.concat()),
+            Payload::Stream(mut reader) => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Sends the payload. Ctrl+C during a --line-delay pause stops the
+    /// sending.
+    fn write_to(
+        self,
+        writer: &mut impl Write,
+        signals: &Signals,
+    ) -> io::Result<()> {
+        match self {
+            Payload::Bytes(bytes) => writer.write_all(&bytes)?,
+            Payload::Messages(messages) => {
+                for message in messages {
+                    writer.write_all(&message)?;
+                }
+            }
+            Payload::Stream(mut reader) => {
+                io::copy(&mut reader, writer)?;
+            }
+            Payload::Lines(text, delay) => {
+                let mut lines = text.split_inclusive(|&b| b == b'\n');
+                if let Some(first) = lines.next() {
+                    writer.write_all(first)?;
+                }
+                for line in lines {
+                    writer.flush()?;
+                    pause(delay, signals);
+                    if signals.interrupted() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Interrupted,
+                            "Interrupted while sending",
+                        ));
+                    }
+                    writer.write_all(line)?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// The bytes of item `index` of a list input, converted like a string or
+/// binary input on its own.
+fn message_bytes(
+    item: Value,
+    index: usize,
+    crlf: bool,
+    encoding: Option<InputEncoding>,
+) -> Result<Vec<u8>, LabeledError> {
+    let span = item.span();
+    match (item, encoding) {
+        (Value::String { val, .. }, Some(encoding)) => {
+            encoding.decode(&val).map_err(|e| {
+                LabeledError::new("Invalid encoded input")
+                    .with_help(format!("Item {}: {}", index, e))
+                    .with_label("in this item", span)
+            })
+        }
+        (Value::String { val, .. }, None) => {
+            Ok(if crlf { to_crlf(&val) } else { val }.into_bytes())
+        }
+        (Value::Binary { val, .. }, None) => Ok(val),
+        (other, Some(_)) => {
+            Err(LabeledError::new("Unsupported input type")
+                .with_help(format!(
+                    "--input-encoding decodes string input, but item {} is {}",
+                    index,
+                    other.get_type()
+                ))
+                .with_label("in this item", span))
+        }
+        (other, None) => Err(LabeledError::new("Unsupported input type")
+            .with_help(format!(
+                "Each item of a list is sent as a message of its own and must be a string or binary, but item {} is {}",
+                index,
+                other.get_type()
+            ))
+            .with_label("in this item", span)),
+    }
+}
+
+/// Formats bytes like `xxd`: the offset, sixteen bytes in hex, and the
+/// same bytes as text with `.` for anything unprintable.
+fn hex_dump(bytes: &[u8]) -> String {
+    let lines: Vec<String> = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let hex: Vec<String> = chunk
+                .chunks(2)
+                .map(|pair| {
+                    pair.iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect()
+                })
+                .collect();
+            let text: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!(
+                "{:08x}: {:<39}  {}",
+                index * 16,
+                hex.join(" "),
+                text
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Turns bare `\n` line endings into `\r\n`, leaving existing `\r\n`
+/// alone.
+fn to_crlf(text: &str) -> String {
+    let mut converted = String::with_capacity(text.len());
+    let mut prev = None;
+    for c in text.chars() {
+        if c == '\n' && prev != Some('\r') {
+            converted.push('\r');
+        }
+        converted.push(c);
+        prev = Some(c);
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::Pool;
+    use std::net::TcpListener;
+
+    /// An exchange whose replies each end at a newline.
+    fn line_exchange() -> Exchange {
+        Exchange {
+            read_timeout: Duration::from_secs(5),
+            deadline: None,
+            shutdown_write: false,
+            interactive: false,
+            read_until: Some(b"\n".to_vec()),
+            length_prefix: None,
+            split_frames: false,
+            strict: false,
+            first_line: false,
+            hex_output: false,
+            from_json: false,
+            max_rate: None,
+            max_size: None,
+            content_type: None,
+            output_file: None,
+            repeat: None,
+            complete: Arc::new(AtomicBool::new(false)),
+            leftover: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The values of a split-frames reply read from `reply`.
+    fn frames(reply: impl Read, strict: bool) -> Vec<Value> {
+        let exchange = Exchange {
+            read_until: None,
+            length_prefix: Some(2),
+            split_frames: true,
+            strict,
+            ..line_exchange()
+        };
+        Frames {
+            reader: reply,
+            width: 2,
+            exchange,
+            done: false,
+            head: Span::test_data(),
+        }
+        .collect()
+    }
+
+    fn binary(value: &Value) -> &[u8] {
+        match value {
+            Value::Binary { val, .. } => val,
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn split_frames_until_the_connection_closes() {
+        let reply: &[u8] = b"\0\x03one\0\0\0\x03two";
+        let frames = frames(reply, false);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(binary(&frames[0]), b"one");
+        assert_eq!(binary(&frames[1]), b"");
+        assert_eq!(binary(&frames[2]), b"two");
+    }
+
+    #[test]
+    fn split_frames_until_the_server_goes_quiet() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(b"\0\x03one\0\x03two").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let frames = frames(client, false);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(binary(&frames[1]), b"two");
+    }
+
+    #[test]
+    fn an_incomplete_last_frame_fails_only_when_strict() {
+        let reply: &[u8] = b"\0\x03one\0\x05tw";
+        let frames_lenient = frames(reply, false);
+        assert_eq!(frames_lenient.len(), 1);
+        assert_eq!(binary(&frames_lenient[0]), b"one");
+
+        let frames_strict = frames(reply, true);
+        assert_eq!(frames_strict.len(), 2);
+        assert!(matches!(frames_strict[1], Value::Error { .. }));
+    }
+
+    #[test]
+    fn list_items_get_a_reply_each() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            io::copy(&mut reader, &mut stream).unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let rounds = Rounds::new(
+            Throttled::new(stream, None),
+            vec![b"first\n".to_vec(), b"second\n".to_vec()],
+            &line_exchange(),
+            None,
+            Signals::empty(),
+            Span::test_data(),
+        );
+        let replies: Vec<Vec<u8>> = rounds
+            .map(|reply| match reply {
+                Value::Binary { val, .. } => val,
+                other => {
+                    panic!("expected a binary reply, got {:?}", other)
+                }
+            })
+            .collect();
+        assert_eq!(
+            replies,
+            [b"first\n".to_vec(), b"second\n".to_vec()]
+        );
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn list_items_without_a_framing_end_at_the_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            io::copy(&mut reader, &mut stream).unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(INTERRUPT_POLL)).unwrap();
+        let exchange = Exchange {
+            read_timeout: Duration::from_millis(300),
+            read_until: None,
+            ..line_exchange()
+        };
+        let rounds = Rounds::new(
+            Throttled::new(stream, None),
+            vec![b"PING".to_vec(), b"INCR visits".to_vec()],
+            &exchange,
+            None,
+            Signals::empty(),
+            Span::test_data(),
+        );
+        let replies: Vec<Value> = rounds.collect();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(binary(&replies[0]), b"PING");
+        assert_eq!(binary(&replies[1]), b"INCR visits");
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn replies_sent_together_stay_apart_on_a_reused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"one\ntwo\n").unwrap();
+            // Open until the client is done.
+            io::copy(&mut stream, &mut io::sink()).unwrap();
+        });
+
+        let pool = Arc::new(Pool::default());
+        let key = PoolKey {
+            host: "127.0.0.1".into(),
+            port: address.port(),
+            bind: None,
+            local_port: None,
+            proxy_protocol: false,
+            ttl: None,
+            no_delay: false,
+            keepalive: None,
+            recv_buffer: None,
+            send_buffer: None,
+            tls: None,
+        };
+        let mut connection =
+            Connection::Tcp(TcpStream::connect(address).unwrap());
+        let mut pending = Vec::new();
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            // Both replies are in by the first read.
+            thread::sleep(Duration::from_millis(100));
+            let exchange = line_exchange();
+            let checkout = Checkout::new(
+                connection,
+                pending,
+                key.clone(),
+                pool.clone(),
+                exchange.complete.clone(),
+                exchange.leftover.clone(),
+            );
+            let mut reply = Vec::new();
+            UntilDelimiter::new(checkout, b"\n".to_vec(), &exchange)
+                .read_to_end(&mut reply)
+                .unwrap();
+            replies.push(reply);
+            (connection, pending) = pool.take(&key).unwrap();
+        }
+        assert_eq!(replies, [b"one\n".to_vec(), b"two\n".to_vec()]);
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn line_delay_splits_binary_input_too() {
+        let input = PipelineData::Value(
+            Value::binary(
+                b"one\ntwo\n\xff".to_vec(),
+                Span::test_data(),
+            ),
+            None,
+        );
+        let payload = Payload::from_input(
+            input,
+            false,
+            Some(Duration::from_millis(1)),
+            None,
+            false,
+            false,
+            Span::test_data(),
+        )
+        .unwrap();
+        assert!(matches!(payload, Payload::Lines(..)));
+
+        let mut sent = Vec::new();
+        payload.write_to(&mut sent, &Signals::empty()).unwrap();
+        assert_eq!(sent, b"one\ntwo\n\xff");
+    }
+
+    fn limited<R: Read>(
+        inner: R,
+        bytes: u64,
+        truncate: bool,
+    ) -> SizeLimited<R> {
+        SizeLimited {
+            inner,
+            max: MaxSize { bytes, truncate },
+            read: 0,
+            over: false,
+        }
+    }
+
+    #[test]
+    fn max_size_fails_only_past_the_limit() {
+        let mut reply = Vec::new();
+        limited(&b"12345"[..], 5, false)
+            .read_to_end(&mut reply)
+            .unwrap();
+        assert_eq!(reply, b"12345");
+
+        let mut reply = Vec::new();
+        let error = limited(&b"123456"[..], 5, false)
+            .read_to_end(&mut reply)
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Reply too large"));
+        assert_eq!(reply, b"12345");
+    }
+
+    #[test]
+    fn truncate_stops_at_the_limit_without_reading_on() {
+        // A reader that fails once its first five bytes are used up
+        // stands for a server that keeps the connection open.
+        let open = (&b"12345"[..]).chain(ErrorReader);
+        let mut reply = Vec::new();
+        limited(open, 5, true).read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"12345");
+
+        let mut reply = Vec::new();
+        limited(&b"123456789"[..], 5, true)
+            .read_to_end(&mut reply)
+            .unwrap();
+        assert_eq!(reply, b"12345");
+    }
+
+    struct ErrorReader;
 
-            Ok(PipelineData::ByteStream(byte_stream, metadata))
+    impl Read for ErrorReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("read past the limit"))
         }
     }
 }
@@ -0,0 +1,59 @@
+// A watchdog for whole-exchange time limits: once the limit passes, it
+// shuts the connection down, which wakes any read or write blocked on it.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub struct Deadline {
+    limit: Duration,
+    expired: Arc<AtomicBool>,
+    /// Dropped with the `Deadline` when the exchange is over, which
+    /// lets the watchdog go without touching the connection.
+    _cancel: mpsc::Sender<()>,
+}
+
+impl Deadline {
+    /// Runs `shutdown` once `limit` has passed, unless the `Deadline` is
+    /// dropped first.
+    pub fn start(
+        limit: Duration,
+        shutdown: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let expired = Arc::new(AtomicBool::new(false));
+        let flag = expired.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) =
+                cancelled.recv_timeout(limit)
+            {
+                flag.store(true, Ordering::SeqCst);
+                shutdown();
+            }
+        });
+        Deadline {
+            limit,
+            expired,
+            _cancel: cancel,
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    /// The error to report instead of whatever the shutdown caused,
+    /// naming the `flag` that set the limit.
+    pub fn error(&self, flag: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "Deadline exceeded: the exchange took longer than the {:?} allowed by {}.",
+                self.limit, flag
+            ),
+        )
+    }
+}
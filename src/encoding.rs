@@ -0,0 +1,181 @@
+// Text encodings of binary input, for `socket connect --input-encoding`,
+// and the ones proxy URLs and credentials use.
+
+use nu_protocol::{LabeledError, Spanned};
+
+/// How string input spells out the bytes to send.
+#[derive(Clone, Copy)]
+pub enum InputEncoding {
+    Hex,
+    Base64,
+}
+
+impl InputEncoding {
+    pub fn parse(name: &Spanned<String>) -> Result<Self, LabeledError> {
+        match name.item.as_str() {
+            "hex" => Ok(InputEncoding::Hex),
+            "base64" => Ok(InputEncoding::Base64),
+            other => Err(LabeledError::new("Invalid input encoding")
+                .with_help(format!(
+                    "Unknown encoding `{}`. Use hex or base64.",
+                    other
+                ))
+                .with_label("here", name.span)),
+        }
+    }
+
+    /// Decodes `text` into the bytes it stands for. Whitespace is
+    /// ignored, so grouped or line-wrapped input works.
+    pub fn decode(self, text: &str) -> Result<Vec<u8>, String> {
+        let chars: Vec<(usize, char)> = text
+            .char_indices()
+            .filter(|(_, c)| !c.is_ascii_whitespace())
+            .collect();
+        match self {
+            InputEncoding::Hex => decode_hex(&chars),
+            InputEncoding::Base64 => decode_base64(&chars),
+        }
+    }
+}
+
+fn decode_hex(chars: &[(usize, char)]) -> Result<Vec<u8>, String> {
+    if !chars.len().is_multiple_of(2) {
+        return Err(format!(
+            "Hex input needs two digits per byte, but has {} digits.",
+            chars.len()
+        ));
+    }
+    let digit = |(index, c): (usize, char)| {
+        c.to_digit(16).ok_or_else(|| {
+            format!("`{}` at position {} is not a hex digit.", c, index)
+        })
+    };
+    chars
+        .chunks(2)
+        .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+/// Standard base64, with or without padding. The URL-safe `-` and `_`
+/// are accepted too.
+fn decode_base64(chars: &[(usize, char)]) -> Result<Vec<u8>, String> {
+    let end = chars
+        .iter()
+        .rposition(|(_, c)| *c != '=')
+        .map_or(0, |last| last + 1);
+    let padding = chars.len() - end;
+    if end % 4 == 1 || (padding > 0 && !chars.len().is_multiple_of(4)) {
+        return Err(
+            "Base64 input has the wrong length; it may be cut off."
+                .to_string(),
+        );
+    }
+
+    let mut bytes = Vec::with_capacity(end * 3 / 4);
+    let mut group: u32 = 0;
+    let mut bits = 0;
+    for &(index, c) in &chars[..end] {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => {
+                return Err(format!(
+                    "`{}` at position {} is not a base64 character.",
+                    c, index
+                ))
+            }
+        };
+        group = group << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+            group &= (1 << bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Standard base64 with padding, as in HTTP Basic authentication.
+pub fn encode_base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let group =
+            u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                output.push(ALPHABET[index as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// Decodes the `%XX` escapes in a part of a URL. The errors leave the
+/// text out, as it may be a password.
+pub fn percent_decode(text: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = after;
+            continue;
+        }
+        let escape = after
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or("A `%` is not followed by two hex digits.")?;
+        bytes.push(escape);
+        rest = &after[2..];
+    }
+    String::from_utf8(bytes).map_err(|_| {
+        "The escapes do not decode to UTF-8 text.".to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trips() {
+        for input in [&b""[..], b"f", b"fo", b"foo", b"user:p@ss\xff"] {
+            let encoded = encode_base64(input);
+            let chars: Vec<(usize, char)> =
+                encoded.char_indices().collect();
+            assert_eq!(decode_base64(&chars).unwrap(), input);
+        }
+        assert_eq!(
+            encode_base64(b"Aladdin:open sesame"),
+            "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(
+            percent_decode("p%40ss%3Aword").unwrap(),
+            "p@ss:word"
+        );
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+        assert!(percent_decode("100%").is_err());
+        assert!(percent_decode("%+1").is_err());
+        assert!(percent_decode("%ff").is_err());
+    }
+}
@@ -0,0 +1,25 @@
+// Checks on flag values that the signature's shapes leave open.
+
+use nu_plugin::EvaluatedCall;
+use nu_protocol::{LabeledError, Spanned};
+use std::time::Duration;
+
+/// The duration given with the flag `name`, if any. Nushell durations
+/// may be negative, which cast to a `Duration` would wrap around to
+/// centuries, so zero and below are rejected.
+pub fn positive_duration(
+    call: &EvaluatedCall,
+    name: &str,
+) -> Result<Option<Duration>, LabeledError> {
+    match call.get_flag::<Spanned<i64>>(name)? {
+        Some(nanos) if nanos.item <= 0 => {
+            Err(LabeledError::new("Invalid duration")
+                .with_help(format!("--{} must be positive.", name))
+                .with_label("here", nanos.span))
+        }
+        Some(nanos) => {
+            Ok(Some(Duration::from_nanos(nanos.item as u64)))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,342 @@
+use super::SocketPlugin;
+use crate::flags::positive_duration;
+use crate::resolve::{host_port, resolve};
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, Category, Example, LabeledError, PipelineData, Signals,
+    Signature, Span, Spanned, SyntaxShape, Value,
+};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub struct Forward;
+
+impl PluginCommand for Forward {
+    type Plugin = SocketPlugin;
+
+    fn name(&self) -> &str {
+        "socket forward"
+    }
+
+    fn description(&self) -> &str {
+        "Relay TCP connections from a local address to a remote one."
+    }
+
+    fn extra_description(&self) -> &str {
+        "Each accepted connection gets its own connection to the target, and bytes are copied both ways until both sides are done. Returns a summary record when it stops."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .required(
+                "host",
+                SyntaxShape::String,
+                "The hostname or IP address to listen on.",
+            )
+            .required(
+                "port",
+                SyntaxShape::Int,
+                "The port to listen on.",
+            )
+            .required(
+                "target-host",
+                SyntaxShape::String,
+                "The host to forward connections to.",
+            )
+            .required(
+                "target-port",
+                SyntaxShape::Int,
+                "The port to forward connections to.",
+            )
+            .switch(
+                "single",
+                "Stop after forwarding a single connection.",
+                Some('s'),
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "Close a connection when no data has moved in either direction for this long. Connections may idle forever by default.",
+                Some('t'),
+            )
+            .category(Category::Network)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "socket forward 127.0.0.1 8080 example.com 80",
+                description: "Make a web server reachable on a local port.",
+                result: None,
+            },
+            Example {
+                example: "socket forward 0.0.0.0 2222 10.0.0.5 22 --timeout 10min",
+                description: "Relay SSH to an internal host, dropping connections idle for ten minutes.",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let head = call.head;
+        let host: String = call.req(0)?;
+        let port = port_arg(call.req(1)?)?;
+        let target_host: Spanned<String> = call.req(2)?;
+        let target_port = port_arg(call.req(3)?)?;
+        let single = call.has_flag("single")?;
+        let idle = match call.get_flag::<i64>("timeout")? {
+            Some(0) | None => None,
+            Some(_) => positive_duration(call, "timeout")?,
+        };
+
+        let targets = resolve(
+            &target_host.item,
+            target_port,
+            None,
+            target_host.span,
+        )?;
+
+        let listener = TcpListener::bind(host_port(&host, port))
+            .map_err(|e| {
+                LabeledError::new("Failed to bind to address")
+                    .with_help(e.to_string())
+                    .with_label("here", head)
+            })?;
+        listener.set_nonblocking(true).map_err(|e| {
+            LabeledError::new("Failed to set listener to non-blocking")
+                .with_help(e.to_string())
+                .with_label("here", head)
+        })?;
+        let local_addr = listener
+            .local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        eprintln!(
+            "Forwarding {} to {}:{}... (Press Ctrl+C to stop)",
+            local_addr, target_host.item, target_port
+        );
+
+        let stats = Arc::new(RelayStats::default());
+        let started = Instant::now();
+        let signals = engine.signals().clone();
+        let mut relays: Vec<JoinHandle<()>> = Vec::new();
+        loop {
+            if signals.interrupted() {
+                eprintln!("\nForwarding stopped.");
+                break;
+            }
+            let (client, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Error accepting connection: {}", e);
+                    break;
+                }
+            };
+
+            let targets = targets.clone();
+            let signals = signals.clone();
+            let stats = stats.clone();
+            let relay = thread::spawn(move || {
+                let result =
+                    connect_target(&targets).and_then(|target| {
+                        relay(client, target, idle, &signals, &stats)
+                    });
+                if let Err(e) = result {
+                    eprintln!("Error forwarding {}: {}", peer, e);
+                }
+            });
+            relays.retain(|relay| !relay.is_finished());
+            relays.push(relay);
+            if single {
+                break;
+            }
+        }
+
+        // The relays notice Ctrl+C on their own, so this does not hang.
+        for relay in relays {
+            let _ = relay.join();
+        }
+        Ok(PipelineData::Value(
+            stats.to_value(started.elapsed(), head),
+            None,
+        ))
+    }
+}
+
+fn port_arg(port: Spanned<i64>) -> Result<u16, LabeledError> {
+    u16::try_from(port.item).map_err(|e| {
+        LabeledError::new("Invalid port number")
+            .with_help(format!(
+                "Port must be between 0 and 65535. Error: {}",
+                e
+            ))
+            .with_label("here", port.span)
+    })
+}
+
+/// How long a connection may take to reach the target.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a relay wakes up to check for Ctrl+C and idleness.
+const POLL: Duration = Duration::from_millis(100);
+
+fn connect_target(targets: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for target in targets {
+        match TcpStream::connect_timeout(target, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, "no target address")
+    }))
+}
+
+/// Totals over all relayed connections.
+#[derive(Default)]
+struct RelayStats {
+    connections: AtomicU64,
+    /// Bytes sent from clients to the target.
+    bytes_up: AtomicU64,
+    /// Bytes sent from the target back to clients.
+    bytes_down: AtomicU64,
+}
+
+impl RelayStats {
+    fn to_value(&self, uptime: Duration, span: Span) -> Value {
+        let count = |counter: &AtomicU64| {
+            counter.load(Ordering::Relaxed).min(i64::MAX as u64) as i64
+        };
+        Value::record(
+            record! {
+                "connections_handled" => Value::int(count(&self.connections), span),
+                "bytes_to_target" => Value::filesize(count(&self.bytes_up), span),
+                "bytes_from_target" => Value::filesize(count(&self.bytes_down), span),
+                "uptime" => Value::duration(
+                    uptime.as_nanos().min(i64::MAX as u128) as i64,
+                    span,
+                ),
+            },
+            span,
+        )
+    }
+}
+
+/// Copies bytes both ways between `client` and `target` until both
+/// directions have ended.
+fn relay(
+    client: TcpStream,
+    target: TcpStream,
+    idle: Option<Duration>,
+    signals: &Signals,
+    stats: &RelayStats,
+) -> io::Result<()> {
+    // Sockets accepted from a non-blocking listener may inherit that mode.
+    client.set_nonblocking(false)?;
+    client.set_read_timeout(Some(POLL))?;
+    target.set_read_timeout(Some(POLL))?;
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+
+    let up = {
+        let from = client.try_clone()?;
+        let to = target.try_clone()?;
+        let last_activity = last_activity.clone();
+        let signals = signals.clone();
+        thread::spawn(move || {
+            pump(from, to, idle, &last_activity, &signals)
+        })
+    };
+    let down = pump(target, client, idle, &last_activity, signals);
+    let up = up.join().unwrap_or_else(|_| {
+        Err(io::Error::other("relay thread panicked"))
+    });
+
+    stats.connections.fetch_add(1, Ordering::Relaxed);
+    stats
+        .bytes_up
+        .fetch_add(*up.as_ref().unwrap_or(&0), Ordering::Relaxed);
+    stats
+        .bytes_down
+        .fetch_add(*down.as_ref().unwrap_or(&0), Ordering::Relaxed);
+    up.and(down).map(|_| ())
+}
+
+/// Copies from `from` to `to` until `from` reaches end-of-file, which is
+/// passed on by shutting down the sending half of `to`, so the other
+/// direction can go on by itself. Returns the number of bytes copied.
+fn pump(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    idle: Option<Duration>,
+    last_activity: &Mutex<Instant>,
+    signals: &Signals,
+) -> io::Result<u64> {
+    let mut buffer = [0u8; 16 * 1024];
+    let mut copied = 0;
+    loop {
+        if signals.interrupted() {
+            close_both(&from, &to);
+            return Ok(copied);
+        }
+        match from.read(&mut buffer) {
+            Ok(0) => {
+                // The peer may already be gone, which is fine here.
+                let _ = to.shutdown(Shutdown::Write);
+                return Ok(copied);
+            }
+            Ok(n) => {
+                if let Err(e) = to.write_all(&buffer[..n]) {
+                    close_both(&from, &to);
+                    return Err(e);
+                }
+                copied += n as u64;
+                *last_activity
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()) =
+                    Instant::now();
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                let quiet = last_activity
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .elapsed();
+                if idle.is_some_and(|idle| quiet >= idle) {
+                    close_both(&from, &to);
+                    return Ok(copied);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                close_both(&from, &to);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Shuts down both connections, which also ends the pump going the other
+/// way.
+fn close_both(a: &TcpStream, b: &TcpStream) {
+    let _ = a.shutdown(Shutdown::Both);
+    let _ = b.shutdown(Shutdown::Both);
+}
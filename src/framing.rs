@@ -0,0 +1,93 @@
+// Length-prefixed framing: each message is preceded by its length as a
+// big-endian integer of a fixed width.
+
+use nu_protocol::{LabeledError, Spanned};
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Checks a `--length-prefixed` value, the width of the length header
+/// in bytes.
+pub fn prefix_width(
+    width: Spanned<i64>,
+) -> Result<usize, LabeledError> {
+    match width.item {
+        1 | 2 | 4 | 8 => Ok(width.item as usize),
+        _ => Err(LabeledError::new("Invalid length prefix")
+            .with_help(
+                "The length prefix must be 1, 2, 4 or 8 bytes wide.",
+            )
+            .with_label("here", width.span)),
+    }
+}
+
+/// Reads one frame and returns its payload. Returns `None` if the stream
+/// ends cleanly before a new frame starts. A frame longer than `limit`
+/// is an error, and is not read.
+pub fn read_frame<R: Read>(
+    reader: &mut R,
+    width: usize,
+    limit: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 8];
+    let mut filled = 0;
+    while filled < width {
+        match reader.read(&mut header[8 - width + filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the connection closed in the middle of a length prefix",
+                ))
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let len = u64::from_be_bytes(header);
+    if len > limit as u64 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "the frame is {} bytes long, more than the limit of {} bytes",
+                len, limit
+            ),
+        ));
+    }
+    // The declared length is not trusted for allocating up front.
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "the connection closed after {} of the {} bytes announced by the length prefix",
+                payload.len(),
+                len
+            ),
+        ));
+    }
+    Ok(Some(payload))
+}
+
+/// Writes `payload` preceded by its length. Returns the number of bytes
+/// written, header included.
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    payload: &[u8],
+    width: usize,
+) -> io::Result<u64> {
+    let len = payload.len() as u64;
+    if width < 8 && len >> (width * 8) != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} bytes do not fit in a {}-byte length prefix",
+                len, width
+            ),
+        ));
+    }
+    writer.write_all(&len.to_be_bytes()[8 - width..])?;
+    writer.write_all(payload)?;
+    Ok(width as u64 + len)
+}
@@ -0,0 +1,185 @@
+use super::SocketPlugin;
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, Category, Example, LabeledError, PipelineData, Signature,
+    Span, Value,
+};
+use std::io;
+use std::net::IpAddr;
+
+pub struct Info;
+
+impl PluginCommand for Info {
+    type Plugin = SocketPlugin;
+
+    fn name(&self) -> &str {
+        "socket info"
+    }
+
+    fn description(&self) -> &str {
+        "List the network interfaces of this host and their addresses."
+    }
+
+    fn extra_description(&self) -> &str {
+        "Shows which addresses can be given to `socket listen` or to `--bind` of `socket connect`."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name()).category(Category::Network)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "socket info",
+                description: "List all interfaces.",
+                result: None,
+            },
+            Example {
+                example: "socket info | where is_up and not is_loopback | get ipv4 | flatten",
+                description: "Find the IPv4 addresses other hosts may reach this one on.",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let head = call.head;
+        let interfaces = interfaces().map_err(|e| {
+            LabeledError::new("Failed to list network interfaces")
+                .with_help(e.to_string())
+                .with_label("here", head)
+        })?;
+        let rows = interfaces
+            .into_iter()
+            .map(|interface| interface.to_value(head))
+            .collect();
+        Ok(PipelineData::Value(Value::list(rows, head), None))
+    }
+}
+
+/// A network interface with all its addresses.
+struct Interface {
+    name: String,
+    addrs: Vec<IpAddr>,
+    is_loopback: bool,
+    is_up: bool,
+}
+
+impl Interface {
+    fn to_value(&self, span: Span) -> Value {
+        let addrs = |v4: bool| {
+            Value::list(
+                self.addrs
+                    .iter()
+                    .filter(|addr| addr.is_ipv4() == v4)
+                    .map(|addr| Value::string(addr.to_string(), span))
+                    .collect(),
+                span,
+            )
+        };
+        Value::record(
+            record! {
+                "name" => Value::string(&self.name, span),
+                "ipv4" => addrs(true),
+                "ipv6" => addrs(false),
+                "is_loopback" => Value::bool(self.is_loopback, span),
+                "is_up" => Value::bool(self.is_up, span),
+            },
+            span,
+        )
+    }
+}
+
+/// Lists the interfaces in the order the system reports them.
+/// `getifaddrs` returns an entry per address, which are gathered by
+/// interface name; interfaces without an IP address are kept too.
+#[cfg(unix)]
+fn interfaces() -> io::Result<Vec<Interface>> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    let mut list: *mut libc::ifaddrs = ptr::null_mut();
+    // SAFETY: `getifaddrs` fills in a linked list that stays valid until
+    // it is handed to `freeifaddrs` below.
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut cursor = list;
+    while !cursor.is_null() {
+        // SAFETY: `cursor` points into the list from `getifaddrs`, and
+        // every pointer read below is checked for null first.
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_name.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let addr = if entry.ifa_addr.is_null() {
+            None
+        } else {
+            // SAFETY: the address family says which struct the pointer
+            // refers to.
+            unsafe {
+                match i32::from((*entry.ifa_addr).sa_family) {
+                    libc::AF_INET => {
+                        let addr = &*(entry.ifa_addr
+                            as *const libc::sockaddr_in);
+                        Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                            addr.sin_addr.s_addr,
+                        ))))
+                    }
+                    libc::AF_INET6 => {
+                        let addr = &*(entry.ifa_addr
+                            as *const libc::sockaddr_in6);
+                        Some(IpAddr::V6(Ipv6Addr::from(
+                            addr.sin6_addr.s6_addr,
+                        )))
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        let flags = entry.ifa_flags;
+        let index = match interfaces
+            .iter()
+            .position(|known| known.name == name)
+        {
+            Some(index) => index,
+            None => {
+                interfaces.push(Interface {
+                    name,
+                    addrs: Vec::new(),
+                    is_loopback: flags & libc::IFF_LOOPBACK as u32 != 0,
+                    is_up: flags & libc::IFF_UP as u32 != 0,
+                });
+                interfaces.len() - 1
+            }
+        };
+        interfaces[index].addrs.extend(addr);
+    }
+
+    // SAFETY: `list` came from `getifaddrs` and is not used afterwards.
+    unsafe { libc::freeifaddrs(list) };
+    Ok(interfaces)
+}
+
+#[cfg(not(unix))]
+fn interfaces() -> io::Result<Vec<Interface>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "listing interfaces is only available on Unix-like systems",
+    ))
+}
@@ -0,0 +1,69 @@
+// Conversions between Nushell values and JSON.
+
+use nu_protocol::{Record, Span, Value};
+use serde_json::{Map, Number, Value as Json};
+
+/// Converts a value the way `to json` does: file sizes and durations
+/// become integers, dates RFC 3339 strings, and binary a list of bytes.
+pub fn to_json(value: &Value) -> Result<Json, String> {
+    Ok(match value {
+        Value::Nothing { .. } => Json::Null,
+        Value::Bool { val, .. } => Json::Bool(*val),
+        Value::Int { val, .. } => Json::from(*val),
+        Value::Float { val, .. } => {
+            Number::from_f64(*val).map_or(Json::Null, Json::Number)
+        }
+        Value::String { val, .. } => Json::String(val.clone()),
+        Value::Filesize { val, .. } => Json::from(val.get()),
+        Value::Duration { val, .. } => Json::from(*val),
+        Value::Date { val, .. } => Json::String(val.to_rfc3339()),
+        Value::Binary { val, .. } => Json::Array(
+            val.iter().map(|&byte| Json::from(byte)).collect(),
+        ),
+        Value::List { vals, .. } => Json::Array(
+            vals.iter().map(to_json).collect::<Result<_, _>>()?,
+        ),
+        Value::Record { val, .. } => Json::Object(
+            val.iter()
+                .map(|(key, value)| Ok((key.clone(), to_json(value)?)))
+                .collect::<Result<Map<_, _>, String>>()?,
+        ),
+        other => {
+            return Err(format!(
+                "A {} cannot be converted to JSON.",
+                other.get_type()
+            ))
+        }
+    })
+}
+
+/// Converts parsed JSON the way `from json` does: integers that fit in
+/// 64 bits stay integers, other numbers become floats, and objects keep
+/// their key order.
+pub fn from_json(json: Json, span: Span) -> Value {
+    match json {
+        Json::Null => Value::nothing(span),
+        Json::Bool(val) => Value::bool(val, span),
+        Json::Number(number) => match number.as_i64() {
+            Some(val) => Value::int(val, span),
+            None => {
+                Value::float(number.as_f64().unwrap_or(f64::NAN), span)
+            }
+        },
+        Json::String(val) => Value::string(val, span),
+        Json::Array(items) => Value::list(
+            items
+                .into_iter()
+                .map(|item| from_json(item, span))
+                .collect(),
+            span,
+        ),
+        Json::Object(fields) => Value::record(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, from_json(value, span)))
+                .collect::<Record>(),
+            span,
+        ),
+    }
+}
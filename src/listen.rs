@@ -1,13 +1,36 @@
 use super::SocketPlugin;
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::flags::positive_duration;
+use crate::framing;
+use crate::proxy_protocol;
+use crate::resolve::host_port;
+use crate::tls;
 use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
 use nu_protocol::{
-    engine::Closure, Category, Example, LabeledError, PipelineData,
-    ShellError, Signature, Spanned, SyntaxShape, Value,
+    engine::Closure, record, ByteStream, ByteStreamType, Category,
+    Example, LabeledError, ListStream, PipelineData, ShellError,
+    SignalAction, Signals, Signature, Span, Spanned, SyntaxShape,
+    Value,
 };
-use std::io::{ErrorKind, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
-use std::time::Duration;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::iter;
+use std::net::{
+    IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream,
+    ToSocketAddrs, UdpSocket,
+};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 pub struct Listen;
 
@@ -22,19 +45,273 @@ impl PluginCommand for Listen {
     }
     fn signature(&self) -> Signature {
         Signature::build(self.name())
-            .required("host", SyntaxShape::String, "The hostname or IP address to listen on.")
-            .required("port", SyntaxShape::Int, "The port to listen on.")
-            .required( "closure", SyntaxShape::Closure(Some(vec![SyntaxShape::Binary])), "The closure to run for each connection. It receives the request as binary.")
-                        .switch("single", "Terminate the server after handling a single connection.", Some('s'))
-
+            .required(
+                "host",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::String,
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                ]),
+                "The hostname or IP address to listen on, or a list of them.",
+            )
+            .required(
+                "port",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::Int,
+                    SyntaxShape::List(Box::new(SyntaxShape::Int)),
+                ]),
+                "The port to listen on, or a list of them. Every host is bound on every port. Ignored with --unix.",
+            )
+            .optional(
+                "closure",
+                SyntaxShape::Closure(Some(vec![
+                    SyntaxShape::Binary,
+                    SyntaxShape::Record(vec![]),
+                ])),
+                "The closure to run for each connection. It receives the request as binary and, optionally, a record describing the connection. Not used with --observe, --respond or --respond-file.",
+            )
+            .switch(
+                "single",
+                "Terminate the server after handling a single connection.",
+                Some('s'),
+            )
+            .named(
+                "count",
+                SyntaxShape::Int,
+                "Terminate the server after handling this many connections. --single is the same as --count 1.",
+                Some('n'),
+            )
+            .named(
+                "drain-timeout",
+                SyntaxShape::Duration,
+                "How long to wait for running handlers to drain when the server stops. Waits for all of them by default; a second Ctrl+C stops at once.",
+                None,
+            )
+            .named(
+                "shutdown-after",
+                SyntaxShape::Duration,
+                "Stop the server once it has run this long, as if Ctrl+C was pressed.",
+                None,
+            )
+            .switch(
+                "persistent",
+                "Keep each connection open and run the closure once per request until the client disconnects or goes idle.",
+                Some('p'),
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "How long to wait for a client to send its request, or to accept the reply. Defaults to 10 seconds; 0 waits forever.",
+                Some('t'),
+            )
+            .named(
+                "connection-deadline",
+                SyntaxShape::Duration,
+                "Close a connection that is still open this long after it was accepted, however busy it is. Guards against clients that trickle in data to dodge --timeout.",
+                None,
+            )
+            .named(
+                "tls-cert",
+                SyntaxShape::Filepath,
+                "PEM file with the certificate chain to serve TLS with. Requires --tls-key.",
+                None,
+            )
+            .named(
+                "tls-key",
+                SyntaxShape::Filepath,
+                "PEM file with the private key for --tls-cert.",
+                None,
+            )
+            .switch(
+                "reuse-addr",
+                "Set SO_REUSEADDR, so the port can be bound again right after a restart.",
+                None,
+            )
+            .switch(
+                "reuse-port",
+                "Set SO_REUSEPORT, so several listeners can share the port. Not available on Windows.",
+                None,
+            )
+            .switch(
+                "observe",
+                "Run no closure and send nothing back: output each request as a record with its sender, arrival time and data, as it comes in.",
+                None,
+            )
+            .named(
+                "respond",
+                SyntaxShape::String,
+                "Answer every request with this text instead of running a closure.",
+                None,
+            )
+            .named(
+                "respond-file",
+                SyntaxShape::Filepath,
+                "Answer every request with the contents of this file instead of running a closure.",
+                None,
+            )
+            .switch(
+                "print-port",
+                "Output the port the server listens on as soon as it is bound, before the statistics. Useful with port 0, which lets the system choose.",
+                None,
+            )
+            .switch(
+                "dual-stack",
+                "Listen on all interfaces for both IPv4 and IPv6 clients with one socket. Requires host :: or 0.0.0.0.",
+                None,
+            )
+            .named(
+                "backlog",
+                SyntaxShape::Int,
+                "How many connections the system may queue until they are accepted. Defaults to 128; the system may lower it.",
+                None,
+            )
+            .switch(
+                "proxy-protocol",
+                "Expect each connection to start with a PROXY protocol v1 or v2 header from a load balancer, and give the closure the client address from it.",
+                None,
+            )
+            .named(
+                "length-prefixed",
+                SyntaxShape::Int,
+                "Frame each request and response with a big-endian length header of this many bytes: 1, 2, 4 or 8.",
+                None,
+            )
+            .switch(
+                "stream",
+                "Pass the request to the closure as a byte stream on $in instead of as its first argument.",
+                None,
+            )
+            .switch(
+                "udp",
+                "Answer UDP datagrams instead of TCP connections. The closure's result is sent back to the sender.",
+                Some('u'),
+            )
+            .switch(
+                "unix",
+                "Treat host as the path of a Unix domain socket to listen on.",
+                None,
+            )
+            .named(
+                "max-request-size",
+                SyntaxShape::Filesize,
+                "Reject requests larger than this. Unlimited by default.",
+                None,
+            )
+            .named(
+                "request-log-format",
+                SyntaxShape::String,
+                "Print a line to stderr for each connection, e.g. \"{time} {peer} {bytes_in}->{bytes_out} {status}\".",
+                None,
+            )
+            .named(
+                "log",
+                SyntaxShape::Filepath,
+                "Append a line of JSON for each connection to this file, with its time, peer, bytes in and out, duration and status.",
+                None,
+            )
+            .named(
+                "until-closure",
+                SyntaxShape::Closure(Some(vec![
+                    SyntaxShape::Binary,
+                    SyntaxShape::Binary,
+                ])),
+                "Run after each connection with the request and response. Return false to stop the server.",
+                None,
+            )
+            .named(
+                "max-connections",
+                SyntaxShape::Int,
+                "Handle at most this many connections at once. Further clients wait until one finishes.",
+                None,
+            )
+            .named(
+                "per-ip-connection-limit",
+                SyntaxShape::Int,
+                "Refuse new connections from a client IP that already has this many open.",
+                None,
+            )
             .category(Category::Network)
     }
     fn examples(&self) -> Vec<Example<'_>> {
-        vec![Example {
-            example: r#"socket listen 0.0.0.0 8080 { |request| "Hello, you sent: " ++ ($request | decode) }"#,
-            description: "Start a simple echo server on port 8080.",
-            result: None,
-        }]
+        vec![
+            Example {
+                example: r#"socket listen 0.0.0.0 8080 { |request| "Hello, you sent: " ++ ($request | decode) }"#,
+                description: "Start a simple echo server on port 8080.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 0.0.0.0 8080 { |request, meta| $"($meta.remote_addr) said ($request | decode)" }"#,
+                description:
+                    "Use the connection record to see who connected.",
+                result: None,
+            },
+            Example {
+                example: "socket listen [127.0.0.1 ::1] [8080 8081] { |request| $request }",
+                description: "Serve on several addresses and ports at once.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 127.0.0.1 8080 --request-log-format "{time} {peer} {bytes_in}->{bytes_out} {status}" { |request| $request }"#,
+                description: "Log a line per connection to stderr.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 127.0.0.1 8080 --until-closure { |request, response| ($request | decode) != "shutdown" } { |request| "ok" }"#,
+                description:
+                    "Stop the server once a client sends \"shutdown\".",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 127.0.0.1 7000 --persistent { |line| $"> ($line | decode)" }"#,
+                description: "Answer every message a client sends over the same connection.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 127.0.0.1 7001 --length-prefixed 4 --persistent { |payload| $payload | bytes reverse }"#,
+                description: "Answer 4-byte length-prefixed binary messages.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 0.0.0.0 8443 --tls-cert cert.pem --tls-key key.pem { |request| "HTTP/1.0 200 OK\r\n\r\nhello" }"#,
+                description: "Serve over TLS with a certificate and key in PEM format.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 127.0.0.1 8080 --respond "HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nok""#,
+                description: "Stand in for a health check endpoint that always answers OK.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 0.0.0.0 2323 --observe | each { |hit| print $"($hit.from) sent ($hit.data | length) bytes" }"#,
+                description: "Log what clients send without answering them.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 0.0.0.0 9000 --stream { |_, meta| $in | save --raw $"upload-($meta.remote_port).bin"; "stored" }"#,
+                description: "Save uploads to disk as they arrive, without holding them in memory.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 0.0.0.0 8000 { |request| open --raw big.iso }"#,
+                description: "Serve a large file, streaming it to each client as it is read.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 127.0.0.1 5353 --udp { |datagram, meta| $datagram }"#,
+                description: "Echo every UDP datagram back to its sender.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen --unix /tmp/echo.sock 0 { |request| $request }"#,
+                description:
+                    "Serve an echo server on a Unix domain socket.",
+                result: None,
+            },
+            Example {
+                example: r#"socket listen 0.0.0.0 8080 --proxy-protocol { |request, meta| $"Hello, ($meta.remote_addr)\n" }"#,
+                description: "Greet clients by their real address from behind a PROXY protocol load balancer.",
+                result: None,
+            },
+        ]
     }
 
     fn run(
@@ -45,131 +322,2505 @@ impl PluginCommand for Listen {
         _input: PipelineData,
     ) -> Result<PipelineData, LabeledError> {
         let head = call.head;
-        let host: String = call.req(0)?;
-        let port: i64 = call.req(1)?;
-        let closure: Closure = call.req(2)?;
-        let is_single_shot = call.has_flag("single")?;
-
-        let addr = format!("{}:{}", host, port);
-        let listener = TcpListener::bind(&addr).map_err(|e| {
-            LabeledError::new("Failed to bind to address")
-                .with_help(e.to_string())
-                .with_label("here", head)
-        })?;
+        let hosts = one_or_many(call.req(0)?, "host")?
+            .into_iter()
+            .map(|host| host.coerce_into_string())
+            .collect::<Result<Vec<_>, _>>()?;
+        let ports = one_or_many(call.req(1)?, "port")?
+            .into_iter()
+            .map(|port| port.as_int())
+            .collect::<Result<Vec<_>, _>>()?;
+        let observe = call.has_flag("observe")?;
+        // A fixed response, with the flag it came from.
+        let fixed = match (
+            call.get_flag::<Spanned<String>>("respond")?,
+            call.get_flag::<Spanned<String>>("respond-file")?,
+        ) {
+            (Some(text), None) => {
+                Some((text.item.into_bytes(), "respond"))
+            }
+            (None, Some(path)) => {
+                let cwd = PathBuf::from(engine.get_current_dir()?);
+                let bytes =
+                    fs::read(cwd.join(&path.item)).map_err(|e| {
+                        LabeledError::new(
+                            "Failed to read response file",
+                        )
+                        .with_help(format!("{}: {}", path.item, e))
+                        .with_label("here", path.span)
+                    })?;
+                Some((bytes, "respond-file"))
+            }
+            (Some(_), Some(path)) => return Err(LabeledError::new(
+                "Invalid flag combination",
+            )
+            .with_help(
+                "--respond and --respond-file cannot be used together.",
+            )
+            .with_label("here", path.span)),
+            (None, None) => None,
+        };
+        let (mode, events) =
+            match (call.opt::<Closure>(2)?, observe, fixed) {
+                (Some(closure), false, None) => {
+                    (Mode::Reply(closure), None)
+                }
+                (None, true, None) => {
+                    let (sender, events) = mpsc::channel();
+                    let dropped = Arc::new(AtomicBool::new(false));
+                    let events = Observed {
+                        events,
+                        dropped: dropped.clone(),
+                    };
+                    (Mode::Observe(sender, dropped), Some(events))
+                }
+                (None, false, Some((bytes, _))) => {
+                    (Mode::Fixed(bytes.into()), None)
+                }
+                (Some(_), true, _) => {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help("--observe does not run a closure. Leave the closure out.")
+                        .with_label("here", call.positional[2].span()))
+                }
+                (Some(_), false, Some((_, flag))) => {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help(format!("--{} answers in place of a closure. Leave the closure out.", flag))
+                        .with_label("here", call.positional[2].span()))
+                }
+                (None, true, Some((_, flag))) => {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help(format!(
+                            "--{} cannot be used with --observe, which sends nothing back.",
+                            flag
+                        ))
+                        .with_label("here", head))
+                }
+                (None, false, None) => {
+                    return Err(LabeledError::new("Missing closure")
+                        .with_help("A closure to answer requests with is required unless --observe, --respond or --respond-file is given.")
+                        .with_label("here", head))
+                }
+            };
+        let count = match (
+            call.has_flag("single")?,
+            call.get_flag::<Spanned<i64>>("count")?,
+        ) {
+            (true, Some(count)) => {
+                return Err(LabeledError::new("Invalid flag combination")
+                    .with_help("--single and --count cannot be used together; --single is the same as --count 1.")
+                    .with_label("here", count.span))
+            }
+            (true, None) => Some(1),
+            (false, Some(count)) if count.item < 1 => {
+                return Err(LabeledError::new("Invalid count")
+                    .with_help("--count must be at least 1.")
+                    .with_label("here", count.span))
+            }
+            (false, Some(count)) => Some(count.item as u64),
+            (false, None) => None,
+        };
+        let use_unix = call.has_flag("unix")?;
+        let use_udp = call.has_flag("udp")?;
+        let print_port = call.has_flag("print-port")?;
+        if print_port && use_unix {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help("--print-port cannot be used with --unix, which has no port.")
+                .with_label("here", head));
+        }
+        let until_closure: Option<Closure> =
+            call.get_flag("until-closure")?;
+        if observe {
+            let rejected = [
+                ("stream", call.has_flag("stream")?),
+                ("until-closure", until_closure.is_some()),
+                ("print-port", print_port),
+            ];
+            if let Some((flag, _)) =
+                rejected.iter().find(|(_, set)| *set)
+            {
+                return Err(LabeledError::new(
+                    "Invalid flag combination",
+                )
+                .with_help(format!(
+                    "--{} cannot be used with --observe.",
+                    flag
+                ))
+                .with_label("here", head));
+            }
+        }
+        if matches!(mode, Mode::Fixed(_)) && call.has_flag("stream")? {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help("--stream passes the request to a closure, so it cannot be used with --respond or --respond-file.")
+                .with_label("here", head));
+        }
 
-        // Set the listener to non-blocking mode.
-        listener.set_nonblocking(true).map_err(|e| {
-            LabeledError::new("Failed to set listener to non-blocking")
-                .with_help(e.to_string())
-                .with_label("here", head)
-        })?;
+        let log_format = match call
+            .get_flag::<Spanned<String>>("request-log-format")?
+        {
+            Some(template) => {
+                Some(LogFormat::parse(&template.item).map_err(|e| {
+                    LabeledError::new("Invalid request log format")
+                        .with_help(e)
+                        .with_label("in this template", template.span)
+                })?)
+            }
+            None => None,
+        };
+        let log_file = match call.get_flag::<Spanned<String>>("log")? {
+            Some(path) => {
+                let cwd = PathBuf::from(engine.get_current_dir()?);
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(cwd.join(&path.item))
+                    .map_err(|e| {
+                        LabeledError::new("Failed to open log file")
+                            .with_help(format!("{}: {}", path.item, e))
+                            .with_label("here", path.span)
+                    })?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+        let request_log = RequestLog {
+            format: log_format,
+            file: log_file,
+        };
 
-        eprintln!("Listening on {}... (Press Ctrl+C to stop)", addr);
+        let drain_timeout = positive_duration(call, "drain-timeout")?;
+        let shutdown_after = positive_duration(call, "shutdown-after")?;
 
-        loop {
-            // 1. Check for the signal at the beginning of every single loop iteration.
-            if engine.signals().interrupted() {
-                eprintln!("\nServer shutting down.");
-                break;
+        // `0sec` waits forever. The config's timeout is checked to be
+        // positive on loading.
+        let read_timeout = match call.get_flag::<i64>("timeout")? {
+            Some(0) => None,
+            Some(_) => positive_duration(call, "timeout")?,
+            None => Some(Duration::from_nanos(
+                Config::load(engine)?.timeout.unwrap_or(10_000_000_000)
+                    as u64,
+            )),
+        };
+        let connection_deadline =
+            positive_duration(call, "connection-deadline")?;
+
+        let max_request_size = match call
+            .get_flag::<Spanned<i64>>("max-request-size")?
+        {
+            Some(size) if size.item < 0 => {
+                return Err(LabeledError::new("Invalid request size")
+                    .with_help("The size cannot be negative.")
+                    .with_label("here", size.span))
+            }
+            Some(size) => Some(size.item as usize),
+            None => None,
+        };
+
+        let per_ip_limit = match call
+            .get_flag::<Spanned<i64>>("per-ip-connection-limit")?
+        {
+            Some(limit) if limit.item < 1 => {
+                return Err(LabeledError::new(
+                    "Invalid connection limit",
+                )
+                .with_help("The limit must be at least 1.")
+                .with_label("here", limit.span))
+            }
+            Some(limit) => Some(PerIpLimit::new(limit.item as usize)),
+            None => None,
+        };
+
+        let max_connections =
+            match call.get_flag::<Spanned<i64>>("max-connections")? {
+                Some(limit) if limit.item < 1 => {
+                    return Err(LabeledError::new(
+                        "Invalid connection limit",
+                    )
+                    .with_help("The limit must be at least 1.")
+                    .with_label("here", limit.span))
+                }
+                Some(limit) => {
+                    Some(ConnectionLimit::new(limit.item as usize))
+                }
+                None => None,
+            };
+
+        let tls_cert: Option<Spanned<String>> =
+            call.get_flag("tls-cert")?;
+        let tls_key: Option<Spanned<String>> =
+            call.get_flag("tls-key")?;
+        // Load the certificate before binding, so a bad file fails the
+        // command right away instead of every connection.
+        let tls_config = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => {
+                if use_unix {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help("TLS is only supported for TCP listeners, not --unix.")
+                        .with_label("here", cert.span));
+                }
+                let cwd = PathBuf::from(engine.get_current_dir()?);
+                let config = tls::server_config(
+                    &cwd.join(&cert.item),
+                    &cwd.join(&key.item),
+                )
+                .map_err(|e| {
+                    LabeledError::new("Failed to load TLS certificate")
+                        .with_help(e)
+                        .with_label("here", cert.span)
+                })?;
+                Some(config)
+            }
+            (Some(flag), None) | (None, Some(flag)) => {
+                return Err(LabeledError::new("Incomplete TLS setup")
+                    .with_help("--tls-cert and --tls-key must be given together.")
+                    .with_label("here", flag.span));
             }
+            (None, None) => None,
+        };
+
+        let length_prefix = match call.get_flag("length-prefixed")? {
+            Some(width) => Some(framing::prefix_width(width)?),
+            None => None,
+        };
+
+        let stream_request = call.has_flag("stream")?;
+        if stream_request {
+            let rejected = [
+                ("udp", use_udp),
+                ("length-prefixed", length_prefix.is_some()),
+                ("persistent", call.has_flag("persistent")?),
+                ("tls-cert", tls_config.is_some()),
+                ("max-request-size", max_request_size.is_some()),
+                ("until-closure", until_closure.is_some()),
+            ];
+            if let Some((flag, _)) =
+                rejected.iter().find(|(_, set)| *set)
+            {
+                return Err(LabeledError::new(
+                    "Invalid flag combination",
+                )
+                .with_help(format!(
+                    "--{} cannot be used with --stream.",
+                    flag
+                ))
+                .with_label("here", head));
+            }
+        }
 
-            // 2. Try to accept a connection.
-            match listener.accept() {
-                Ok((stream, _addr)) => {
-                    // A client connected! Handle it in a new thread like before.
-                    let engine = engine.clone();
-                    let closure = closure.clone();
-                    let head = head;
-
-                    thread::spawn(move || {
-                        if let Err(e) = handle_connection(
-                            engine, stream, closure, head,
-                        ) {
-                            eprintln!(
-                                "Error in connection handler: {:?}",
-                                e
+        let proxy_protocol = call.has_flag("proxy-protocol")?;
+        if use_udp {
+            let rejected = [
+                ("unix", use_unix),
+                ("persistent", call.has_flag("persistent")?),
+                ("length-prefixed", length_prefix.is_some()),
+                ("tls-cert", tls_config.is_some()),
+                (
+                    "max-connections",
+                    call.get_flag_value("max-connections").is_some(),
+                ),
+                ("per-ip-connection-limit", per_ip_limit.is_some()),
+                ("reuse-port", call.has_flag("reuse-port")?),
+                ("connection-deadline", connection_deadline.is_some()),
+                ("proxy-protocol", proxy_protocol),
+            ];
+            if let Some((flag, _)) =
+                rejected.iter().find(|(_, set)| *set)
+            {
+                return Err(LabeledError::new(
+                    "Invalid flag combination",
+                )
+                .with_help(format!(
+                    "--{} cannot be used with --udp.",
+                    flag
+                ))
+                .with_label("here", head));
+            }
+        }
+
+        let backlog = match call.get_flag::<Spanned<i64>>("backlog")? {
+            Some(backlog) => {
+                if use_udp {
+                    return Err(LabeledError::new("Invalid flag combination")
+                        .with_help("--backlog cannot be used with --udp, which accepts no connections.")
+                        .with_label("here", backlog.span));
+                }
+                Some(
+                    i32::try_from(backlog.item)
+                        .ok()
+                        .filter(|&backlog| backlog > 0)
+                        .ok_or_else(|| {
+                            LabeledError::new("Invalid backlog")
+                                .with_help(
+                                    "--backlog must be at least 1.",
+                                )
+                                .with_label("here", backlog.span)
+                        })?,
+                )
+            }
+            None => None,
+        };
+        let bind_options = BindOptions {
+            reuse_addr: call.has_flag("reuse-addr")?,
+            reuse_port: call.has_flag("reuse-port")?,
+            dual_stack: call.has_flag("dual-stack")?,
+            backlog,
+        };
+        if use_unix
+            && (bind_options.reuse_addr || bind_options.reuse_port)
+        {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help("--reuse-addr and --reuse-port only apply to TCP listeners, not --unix.")
+                .with_label("here", head));
+        }
+        if bind_options.dual_stack {
+            if use_unix || use_udp {
+                return Err(LabeledError::new("Invalid flag combination")
+                    .with_help("--dual-stack only applies to TCP listeners, not --unix or --udp.")
+                    .with_label("here", head));
+            }
+            if hosts.iter().any(|host| {
+                !matches!(host.as_str(), "::" | "[::]" | "0.0.0.0")
+            }) {
+                return Err(LabeledError::new("Invalid host for --dual-stack")
+                    .with_help("--dual-stack listens on all interfaces, so host must be :: or 0.0.0.0.")
+                    .with_label("here", call.positional[0].span()));
+            }
+        }
+
+        let addrs: Vec<String> = if use_unix {
+            hosts
+        } else if bind_options.dual_stack {
+            // One dual-stack socket per port already covers every host.
+            ports.iter().map(|port| format!("[::]:{}", port)).collect()
+        } else {
+            hosts
+                .iter()
+                .flat_map(|host| {
+                    ports.iter().map(move |port| host_port(host, port))
+                })
+                .collect()
+        };
+        if use_udp && addrs.len() > 1 {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help("--udp can only listen on a single address.")
+                .with_label("here", head));
+        }
+
+        let handler = Handler {
+            engine: engine.clone(),
+            mode,
+            until_closure,
+            max_request_size,
+            read_timeout,
+            connection_deadline,
+            tls_config,
+            persistent: call.has_flag("persistent")?,
+            stream_request,
+            length_prefix,
+            proxy_protocol,
+            per_ip: per_ip_limit,
+            head,
+        };
+        let stats = Arc::new(Stats::default());
+        let started = Instant::now();
+        let shutdown_at = shutdown_after.map(|after| started + after);
+
+        // Bind first, so address problems are reported right away, then
+        // serve until the server stops and report its statistics.
+        let (local_addrs, serve): (Vec<String>, Serve) = if use_udp {
+            let addr = addrs[0].clone();
+            let socket = UdpSocket::bind(&addr)
+                .map_err(|e| bind_error(&addr, e, false, true, head))?;
+            let local_addr = socket
+                .local_addr()
+                .map(|local| local.to_string())
+                .unwrap_or(addr);
+            eprintln!(
+                "Listening for UDP datagrams on {}... (Press Ctrl+C to stop)",
+                local_addr
+            );
+            let bound = vec![local_addr];
+            let local_addrs = bound.clone();
+            let serve = move || {
+                serve_udp(
+                    &socket,
+                    &handler,
+                    &request_log,
+                    count,
+                    shutdown_at,
+                    &stats,
+                )
+                .map_err(|e| {
+                    LabeledError::new("Failed to receive UDP datagram")
+                        .with_help(e.to_string())
+                        .with_label("here", head)
+                })?;
+                Ok(stats.to_value(started.elapsed(), &bound, head))
+            };
+            (local_addrs, Box::new(serve))
+        } else {
+            let mut listeners = Vec::with_capacity(addrs.len());
+            let mut local_addrs = Vec::with_capacity(addrs.len());
+            for addr in addrs {
+                let listener =
+                    Listener::bind(&addr, use_unix, &bind_options)
+                        .map_err(|e| {
+                            bind_error(&addr, e, use_unix, false, head)
+                        })?;
+
+                // Set the listener to non-blocking mode.
+                listener.set_nonblocking(true).map_err(|e| {
+                    LabeledError::new(
+                        "Failed to set listener to non-blocking",
+                    )
+                    .with_help(e.to_string())
+                    .with_label("here", head)
+                })?;
+
+                let local_addr = listener.local_addr().unwrap_or(addr);
+                let queue = match backlog {
+                    Some(backlog) => format!(
+                        " with a backlog of {}",
+                        effective_backlog(backlog)
+                    ),
+                    None => String::new(),
+                };
+                if bind_options.dual_stack {
+                    eprintln!(
+                        "Listening on {} for IPv4 and IPv6{}... (Press Ctrl+C to stop)",
+                        local_addr, queue
+                    );
+                } else {
+                    eprintln!(
+                        "Listening on {}{}... (Press Ctrl+C to stop)",
+                        local_addr, queue
+                    );
+                }
+                listeners.push(listener);
+                local_addrs.push(local_addr);
+            }
+
+            let limits = Limits {
+                count,
+                max_connections,
+                drain_timeout,
+                shutdown_at,
+            };
+            let bound = local_addrs.clone();
+            let serve = move || {
+                accept_loop(
+                    listeners,
+                    &handler,
+                    &request_log,
+                    &limits,
+                    &stats,
+                );
+                Ok(stats.to_value(started.elapsed(), &bound, head))
+            };
+            (local_addrs, Box::new(serve))
+        };
+
+        // The server runs on its own thread and hands over each request
+        // as it arrives. The events end when the server stops, or as soon
+        // as Ctrl+C is pressed.
+        if let Some(events) = events {
+            thread::spawn(move || {
+                if let Err(e) = serve() {
+                    eprintln!("Error: {:?}", e);
+                }
+            });
+            return Ok(PipelineData::ListStream(
+                ListStream::new(events, head, engine.signals().clone()),
+                None,
+            ));
+        }
+
+        if !print_port {
+            return Ok(PipelineData::Value(serve()?, None));
+        }
+
+        // Emit the ports right away and the statistics once the server,
+        // now running on its own thread, stops.
+        let ports: Vec<Value> = local_addrs
+            .iter()
+            .map(|addr| {
+                let port = addr
+                    .rsplit_once(':')
+                    .and_then(|(_, port)| port.parse::<i64>().ok())
+                    .unwrap_or_default();
+                Value::int(port, head)
+            })
+            .collect();
+        let server = thread::spawn(serve);
+        let results = ports.into_iter().chain(iter::once_with(
+            move || match server.join() {
+                Ok(Ok(stats)) => stats,
+                Ok(Err(e)) => Value::error(e.into(), head),
+                Err(_) => Value::error(
+                    ShellError::GenericError {
+                        error: "The server stopped unexpectedly".into(),
+                        msg: "The server thread panicked.".into(),
+                        span: Some(head),
+                        help: None,
+                        inner: vec![],
+                    },
+                    head,
+                ),
+            },
+        ));
+        Ok(PipelineData::ListStream(
+            ListStream::new(results, head, Signals::empty()),
+            None,
+        ))
+    }
+}
+
+/// Reads an argument that may be a single value or a list of them.
+fn one_or_many(
+    value: Value,
+    name: &str,
+) -> Result<Vec<Value>, LabeledError> {
+    let span = value.span();
+    match value {
+        Value::List { vals, .. } if vals.is_empty() => {
+            Err(LabeledError::new(format!("No {} given", name))
+                .with_help(format!(
+                    "Give at least one {} to listen on.",
+                    name
+                ))
+                .with_label("empty list", span))
+        }
+        Value::List { vals, .. } => Ok(vals),
+        value => Ok(vec![value]),
+    }
+}
+
+/// Runs the server until it stops and returns its statistics.
+type Serve = Box<dyn FnOnce() -> Result<Value, LabeledError> + Send>;
+
+/// Accepts connections on `listeners` and serves each on its own thread
+/// until Ctrl+C, `--count` or a closure ends the server.
+fn accept_loop(
+    listeners: Vec<Listener>,
+    handler: &Handler,
+    request_log: &RequestLog,
+    limits: &Limits,
+    stats: &Arc<Stats>,
+) {
+    // Set by a handler thread when `--until-closure` returns false or
+    // the closure asks to close the server.
+    let stop = Arc::new(AtomicBool::new(false));
+    // Handlers still running, so the server can wait for them before
+    // returning instead of cutting their replies short.
+    let mut handlers: Vec<JoinHandle<()>> = Vec::new();
+    let mut accepted: u64 = 0;
+    // Ctrl+C presses, counted so that another one while the running
+    // handlers drain can cut the wait short. The engine's interrupt flag
+    // stays set after the first and is shared, so it is left alone.
+    let presses = Arc::new(AtomicUsize::new(0));
+    let _presses_guard = {
+        let presses = presses.clone();
+        handler
+            .engine
+            .register_signal_handler(Box::new(move |action| {
+                if action == SignalAction::Interrupt {
+                    presses.fetch_add(1, Ordering::SeqCst);
+                }
+            }))
+            .ok()
+    };
+    let mut interrupted = false;
+
+    loop {
+        // 1. Check for the signal at the beginning of every single loop iteration.
+        if handler.engine.signals().interrupted() {
+            eprintln!("\nServer shutting down.");
+            interrupted = true;
+            break;
+        }
+        if stop.load(Ordering::SeqCst) {
+            eprintln!("Server stopped by a closure.");
+            break;
+        }
+        if handler.unobserved() {
+            eprintln!("Server stopped: the --observe output is no longer read.");
+            break;
+        }
+        if past(limits.shutdown_at) {
+            eprintln!("Server stopped by --shutdown-after.");
+            break;
+        }
+
+        // Stop accepting while the server is full. Clients queue up in
+        // the listen backlog until a handler finishes.
+        let slot = match &limits.max_connections {
+            Some(limit) => {
+                match limit.acquire(Duration::from_millis(50)) {
+                    Some(slot) => Some(slot),
+                    None => continue,
+                }
+            }
+            None => None,
+        };
+
+        // 2. Try to accept a connection.
+        match accept_any(&listeners, accepted as usize) {
+            Ok((stream, mut peer)) => {
+                // Behind a load balancer every connection comes from the
+                // balancer, so the client is only known from the header.
+                let per_ip = handler
+                    .per_ip
+                    .as_ref()
+                    .filter(|_| !handler.proxy_protocol);
+                let ip_guard = match (per_ip, peer.ip()) {
+                    (Some(limit), Some(ip)) => {
+                        match limit.acquire(ip) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                eprintln!(
+                                "Refused connection from {}: per-IP connection limit reached",
+                                peer
                             );
+                                continue;
+                            }
                         }
-                    });
-                    if is_single_shot {
-                        break;
                     }
+                    _ => None,
+                };
+
+                // A client connected! Handle it in a new thread like before.
+                let handler = handler.clone();
+                let stop = stop.clone();
+                let request_log = request_log.clone();
+                let stats = stats.clone();
+
+                let spawned = thread::spawn(move || {
+                    // Held until the handler finishes.
+                    let _ip_guard = ip_guard;
+                    let _slot = slot;
+                    let mut exchange = Exchange::default();
+                    let started = Instant::now();
+                    let result = handle_connection(
+                        &handler,
+                        stream,
+                        &mut peer,
+                        &mut exchange,
+                    );
+                    match &result {
+                        Ok(true) => {}
+                        Ok(false) => stop.store(true, Ordering::SeqCst),
+                        Err(e) => eprintln!(
+                            "Error in connection handler: {:?}",
+                            e
+                        ),
+                    }
+                    stats.record(&exchange);
+                    request_log.record(
+                        &peer,
+                        &exchange,
+                        result.is_ok(),
+                        started.elapsed(),
+                    );
+                });
+                handlers.retain(|handle| !handle.is_finished());
+                handlers.push(spawned);
+                accepted += 1;
+                if limits.count.is_some_and(|count| accepted >= count) {
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                // `WouldBlock` means no client is waiting.
+                // This is our normal "idle" state. We sleep briefly to avoid
+                // consuming 100% of the CPU in a tight loop.
+                thread::sleep(Duration::from_millis(50));
+                continue; // Go to the next loop iteration to check for Ctrl-C again.
+            }
+            Err(e) => {
+                // A real error occurred.
+                eprintln!("Error accepting connection: {}", e);
+                break;
+            }
+        }
+    }
+
+    // Closed first, so clients that connect while the running handlers
+    // drain are refused instead of left waiting in the backlog.
+    drop(listeners);
+    // The press that stopped the server does not cut the wait short.
+    let seen = if interrupted {
+        1
+    } else {
+        presses.load(Ordering::SeqCst)
+    };
+    join_handlers(
+        handlers,
+        limits.drain_timeout,
+        || presses.load(Ordering::SeqCst) > seen,
+        stats,
+    );
+}
+
+/// Limits on the connections the accept loop takes.
+struct Limits {
+    /// Stop after this many connections (--count).
+    count: Option<u64>,
+    max_connections: Option<ConnectionLimit>,
+    /// How long to wait for running handlers when the server stops.
+    drain_timeout: Option<Duration>,
+    /// When to stop accepting (--shutdown-after).
+    shutdown_at: Option<Instant>,
+}
+
+/// Whether the time set by --shutdown-after has come.
+fn past(shutdown_at: Option<Instant>) -> bool {
+    shutdown_at.is_some_and(|at| Instant::now() >= at)
+}
+
+/// Waits for the handler threads to finish, then reports how many were
+/// drained. Handlers still running when the `drain_timeout` ends, or once
+/// `cut_short` says Ctrl+C was pressed again, are left behind.
+fn join_handlers(
+    handlers: Vec<JoinHandle<()>>,
+    drain_timeout: Option<Duration>,
+    cut_short: impl Fn() -> bool,
+    stats: &Stats,
+) {
+    let running = handlers
+        .iter()
+        .filter(|handle| !handle.is_finished())
+        .count();
+    if running > 0 {
+        eprintln!(
+            "Waiting for {} connection(s) to finish. Press Ctrl+C again to stop at once.",
+            running
+        );
+    }
+    let deadline =
+        drain_timeout.map(|timeout| Instant::now() + timeout);
+    let mut pending = handlers;
+    while !pending.is_empty() {
+        let (finished, running): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|handle| handle.is_finished());
+        for handle in finished {
+            let _ = handle.join();
+        }
+        pending = running;
+        if pending.is_empty() {
+            break;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            || cut_short()
+        {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    if running == 0 {
+        return;
+    }
+    // Handlers that were already done when the wait began are not
+    // counted.
+    let abandoned = pending.len();
+    let drained = running.saturating_sub(abandoned);
+    stats.drained.store(drained as u64, Ordering::Relaxed);
+    stats.abandoned.store(abandoned as u64, Ordering::Relaxed);
+    if abandoned == 0 {
+        eprintln!("Drained {} connection(s).", drained);
+    } else {
+        eprintln!(
+            "Drained {} connection(s); gave up on {} still running.",
+            drained, abandoned
+        );
+    }
+}
+
+/// Accepts a waiting connection from any of `listeners`. The search
+/// starts at a different listener each time, so a busy one cannot starve
+/// the rest. Fails with `WouldBlock` when no client is waiting anywhere.
+fn accept_any(
+    listeners: &[Listener],
+    start: usize,
+) -> io::Result<(Connection, Peer)> {
+    for i in 0..listeners.len() {
+        match listeners[(start + i) % listeners.len()].accept() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+    }
+    Err(ErrorKind::WouldBlock.into())
+}
+
+/// Totals over all connections the server has finished handling.
+#[derive(Default)]
+struct Stats {
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Connections still running when the server stopped that finished
+    /// while it waited for them.
+    drained: AtomicU64,
+    /// Connections still running when the server gave up waiting.
+    abandoned: AtomicU64,
+}
+
+impl Stats {
+    fn record(&self, exchange: &Exchange) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add(exchange.bytes_in as u64, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(exchange.bytes_out as u64, Ordering::Relaxed);
+    }
+
+    fn to_value(
+        &self,
+        uptime: Duration,
+        addresses: &[String],
+        span: Span,
+    ) -> Value {
+        let count = |counter: &AtomicU64| {
+            counter.load(Ordering::Relaxed).min(i64::MAX as u64) as i64
+        };
+        Value::record(
+            record! {
+                "addresses" => Value::list(
+                    addresses
+                        .iter()
+                        .map(|address| Value::string(address, span))
+                        .collect(),
+                    span,
+                ),
+                "connections_handled" => Value::int(count(&self.connections), span),
+                "bytes_received" => Value::filesize(count(&self.bytes_in), span),
+                "bytes_sent" => Value::filesize(count(&self.bytes_out), span),
+                "connections_drained" => Value::int(count(&self.drained), span),
+                "connections_abandoned" => Value::int(count(&self.abandoned), span),
+                "uptime" => Value::duration(
+                    uptime.as_nanos().min(i64::MAX as u128) as i64,
+                    span,
+                ),
+            },
+            span,
+        )
+    }
+}
+
+/// A bound listening socket.
+enum Listener {
+    Tcp(TcpListener),
+    /// The path is kept so the socket file can be removed on shutdown.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn bind(
+        addr: &str,
+        unix: bool,
+        options: &BindOptions,
+    ) -> io::Result<Self> {
+        if unix {
+            return Self::bind_unix(addr, options.backlog);
+        }
+        if !options.reuse_addr
+            && !options.reuse_port
+            && !options.dual_stack
+            && options.backlog.is_none()
+        {
+            return Ok(Listener::Tcp(TcpListener::bind(addr)?));
+        }
+
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("No addresses found for {}", addr),
+            )
+        })?;
+        let socket = Socket::new(
+            Domain::for_address(addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        // The standard library always sets SO_REUSEADDR on Unix, so this
+        // keeps that behavior when going through socket2.
+        if options.reuse_addr || cfg!(unix) {
+            socket.set_reuse_address(true)?;
+        }
+        if options.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        if options.dual_stack {
+            socket.set_only_v6(false).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Dual-stack sockets are not available on this system: {}",
+                        e
+                    ),
+                )
+            })?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(options.backlog.unwrap_or(DEFAULT_BACKLOG))?;
+        Ok(Listener::Tcp(socket.into()))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &str, backlog: Option<i32>) -> io::Result<Self> {
+        let listener = match backlog {
+            None => UnixListener::bind(path)?,
+            Some(backlog) => {
+                let socket =
+                    Socket::new(Domain::UNIX, Type::STREAM, None)?;
+                socket.bind(&socket2::SockAddr::unix(path)?)?;
+                socket.listen(backlog)?;
+                socket.into()
+            }
+        };
+        Ok(Listener::Unix(listener, PathBuf::from(path)))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(
+        _path: &str,
+        _backlog: Option<i32>,
+    ) -> io::Result<Self> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Unix domain sockets are only available on Unix-like systems",
+        ))
+    }
+
+    /// The address the listener is bound to, with the port the system
+    /// chose if port 0 was requested.
+    fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => {
+                Ok(listener.local_addr()?.to_string())
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(path.display().to_string()),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(nonblocking)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.set_nonblocking(nonblocking)
+            }
+        }
+    }
+
+    /// Accepts a pending connection. The listener is non-blocking, so
+    /// this returns `WouldBlock` when no client is waiting.
+    fn accept(&self) -> io::Result<(Connection, Peer)> {
+        // Some platforms hand out accepted sockets that inherit the
+        // listener's non-blocking mode, so it is reset explicitly.
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                // IPv4 clients of a dual-stack listener arrive as
+                // IPv4-mapped IPv6 addresses; report them as plain IPv4.
+                let addr = SocketAddr::new(
+                    addr.ip().to_canonical(),
+                    addr.port(),
+                );
+                Ok((Connection::Tcp(stream), Peer::Ip(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                let path =
+                    addr.as_pathname().map(|p| p.display().to_string());
+                Ok((Connection::Unix(stream), Peer::Unix(path)))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Remove the socket file, or the next bind to the same path
+        // fails with "Address already in use".
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Explains a failed bind, with a hint that depends on why it failed.
+fn bind_error(
+    addr: &str,
+    e: io::Error,
+    unix: bool,
+    udp: bool,
+    head: Span,
+) -> LabeledError {
+    let hint = match e.kind() {
+        ErrorKind::PermissionDenied if unix => {
+            "Check that this user may create files in the socket's directory."
+        }
+        ErrorKind::PermissionDenied => {
+            "Ports below 1024 need administrator privileges on most systems. Use a port above 1024, such as 8080."
+        }
+        ErrorKind::AddrInUse if unix => {
+            "A file already exists at this path. Remove it if no server is using it."
+        }
+        // SO_REUSEADDR is always set on Unix, so there the port is
+        // really taken.
+        ErrorKind::AddrInUse if udp || cfg!(unix) => {
+            "Another program is using this port. Stop it or pick another port."
+        }
+        ErrorKind::AddrInUse => {
+            "Another program is listening on this port. If it is a previous server that just exited, --reuse-addr lets the port be bound again right away."
+        }
+        ErrorKind::AddrNotAvailable => {
+            "No interface of this host has this address. Check that the interface exists and is up with `socket info`, or use 0.0.0.0 to listen on all of them."
+        }
+        _ => "",
+    };
+    let help = if hint.is_empty() {
+        format!("{}: {}", addr, e)
+    } else {
+        format!("{}: {}. {}", addr, e, hint)
+    };
+    LabeledError::new("Failed to bind to address")
+        .with_help(help)
+        .with_label("here", head)
+}
+
+/// Socket options that must be set before binding.
+struct BindOptions {
+    reuse_addr: bool,
+    reuse_port: bool,
+    /// Clear IPV6_V6ONLY, so an IPv6 socket also accepts IPv4 clients.
+    dual_stack: bool,
+    /// How many connections may wait to be accepted.
+    backlog: Option<i32>,
+}
+
+/// The listen backlog the standard library uses.
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// Describes the backlog the system will really use. Linux silently
+/// lowers a larger one to `net.core.somaxconn`.
+fn effective_backlog(requested: i32) -> String {
+    #[cfg(target_os = "linux")]
+    if let Some(max) =
+        fs::read_to_string("/proc/sys/net/core/somaxconn")
+            .ok()
+            .and_then(|text| text.trim().parse::<i32>().ok())
+            .filter(|&max| max < requested)
+    {
+        return format!(
+            "{} (lowered from {} by net.core.somaxconn)",
+            max, requested
+        );
+    }
+    requested.to_string()
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin"
+    ))
+))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin"
+    ))
+)))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "SO_REUSEPORT is not available on this platform",
+    ))
+}
+
+/// An accepted client connection.
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<tls::ServerStream>),
+}
+
+impl Connection {
+    /// Wraps a TCP connection in TLS. The handshake happens on the first
+    /// read.
+    fn into_tls(self, config: Arc<ServerConfig>) -> io::Result<Self> {
+        match self {
+            Connection::Tcp(stream) => {
+                let conn = ServerConnection::new(config)
+                    .map_err(io::Error::other)?;
+                Ok(Connection::Tls(Box::new(StreamOwned::new(
+                    conn, stream,
+                ))))
+            }
+            other => Ok(other),
+        }
+    }
+
+    /// Flushes the reply and, for TLS, tells the client no more data is
+    /// coming.
+    fn finish(&mut self) -> io::Result<()> {
+        if let Connection::Tls(stream) = self {
+            stream.conn.send_close_notify();
+        }
+        self.flush()
+    }
+
+    fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(timeout)
+            }
+            Connection::Tls(stream) => {
+                stream.sock.set_read_timeout(timeout)
+            }
+        }
+    }
+
+    fn set_write_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                stream.set_write_timeout(timeout)
+            }
+            Connection::Tls(stream) => {
+                stream.sock.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    /// A second handle for reading, so the request can be streamed while
+    /// this one is kept for the reply. TLS sessions cannot be split.
+    fn try_clone_reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Connection::Tcp(stream) => {
+                Ok(Box::new(stream.try_clone()?))
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                Ok(Box::new(stream.try_clone()?))
+            }
+            Connection::Tls(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "TLS connections cannot be streamed",
+            )),
+        }
+    }
+
+    /// Closes the connection from another thread, waking a handler
+    /// blocked on it.
+    fn closer(&self) -> io::Result<Box<dyn FnOnce() + Send>> {
+        Ok(match self {
+            Connection::Tcp(stream) => {
+                let socket = stream.try_clone()?;
+                Box::new(move || {
+                    let _ = socket.shutdown(Shutdown::Both);
+                })
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                let socket = stream.try_clone()?;
+                Box::new(move || {
+                    let _ = socket.shutdown(Shutdown::Both);
+                })
+            }
+            Connection::Tls(stream) => {
+                let socket = stream.sock.try_clone()?;
+                Box::new(move || {
+                    let _ = socket.shutdown(Shutdown::Both);
+                })
+            }
+        })
+    }
+
+    /// The local end of the connection, if it has a printable address.
+    fn local_addr(&self) -> Option<String> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.local_addr().ok().map(|addr| addr.to_string())
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream
+                .local_addr()
+                .ok()?
+                .as_pathname()
+                .map(|path| path.display().to_string()),
+            Connection::Tls(stream) => stream
+                .sock
+                .local_addr()
+                .ok()
+                .map(|addr| addr.to_string()),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+            // A client closing without close_notify has still finished
+            // sending its request.
+            Connection::Tls(stream) => match stream.read(buf) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+                other => other,
+            },
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// The remote end of an accepted connection.
+#[derive(Clone)]
+enum Peer {
+    Ip(SocketAddr),
+    /// Unix domain socket clients are usually unnamed.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Unix(Option<String>),
+}
+
+impl Peer {
+    fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Ip(addr) => Some(addr.ip()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl Peer {
+    /// The `{ remote_addr, remote_port, local_addr }` record passed to the
+    /// closure. Fields that do not apply to the socket type are null.
+    fn to_record(
+        &self,
+        local_addr: Option<String>,
+        span: Span,
+    ) -> Value {
+        let (remote_addr, remote_port) = match self {
+            Peer::Ip(addr) => (
+                Value::string(addr.ip().to_string(), span),
+                Value::int(addr.port().into(), span),
+            ),
+            Peer::Unix(path) => (
+                path.clone().map_or(Value::nothing(span), |path| {
+                    Value::string(path, span)
+                }),
+                Value::nothing(span),
+            ),
+        };
+        Value::record(
+            record! {
+                "remote_addr" => remote_addr,
+                "remote_port" => remote_port,
+                "local_addr" => local_addr.map_or(
+                    Value::nothing(span),
+                    |addr| Value::string(addr, span),
+                ),
+            },
+            span,
+        )
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Ip(addr) => write!(f, "{}", addr),
+            Peer::Unix(Some(path)) => write!(f, "{}", path),
+            Peer::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+/// A counting semaphore for `--max-connections`.
+struct ConnectionLimit {
+    limit: usize,
+    active: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl ConnectionLimit {
+    fn new(limit: usize) -> Self {
+        ConnectionLimit {
+            limit,
+            active: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Reserves a slot, waiting up to `wait` for one to free up. Returns
+    /// `None` if the server is still full, so the caller can check for
+    /// Ctrl+C before trying again.
+    fn acquire(&self, wait: Duration) -> Option<ConnectionGuard> {
+        let (count, freed) = &*self.active;
+        let count = count.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut count, _) = freed
+            .wait_timeout_while(count, wait, |count| {
+                *count >= self.limit
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard(self.active.clone()))
+    }
+}
+
+/// Releases a `--max-connections` slot when the connection handler
+/// finishes.
+struct ConnectionGuard(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.0;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        freed.notify_one();
+    }
+}
+
+/// Tracks open connections per client IP for
+/// `--per-ip-connection-limit`. Clones share the counts.
+#[derive(Clone)]
+struct PerIpLimit {
+    limit: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PerIpLimit {
+    fn new(limit: usize) -> Self {
+        PerIpLimit {
+            limit,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reserves a slot for `ip`, or returns `None` if it is at the limit.
+    fn acquire(&self, ip: IpAddr) -> Option<PerIpGuard> {
+        let mut active =
+            self.active.lock().unwrap_or_else(|e| e.into_inner());
+        let count = active.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(PerIpGuard {
+            ip,
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// Releases a per-IP slot when the connection handler finishes.
+struct PerIpGuard {
+    ip: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for PerIpGuard {
+    fn drop(&mut self) {
+        let mut active =
+            self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Byte counts for a single connection, used for logging. With
+/// `--persistent` they add up over all requests on the connection.
+#[derive(Default)]
+struct Exchange {
+    bytes_in: usize,
+    bytes_out: usize,
+}
+
+/// One piece of a `--request-log-format` template.
+#[derive(Clone)]
+enum LogPart {
+    Literal(String),
+    Time,
+    Peer,
+    BytesIn,
+    BytesOut,
+    Status,
+}
+
+/// A parsed `--request-log-format` template.
+#[derive(Clone)]
+struct LogFormat(Vec<LogPart>);
+
+impl LogFormat {
+    fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(LogPart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                format!(
+                    "Unclosed placeholder starting at `{}`",
+                    &rest[start..]
+                )
+            })?;
+            let name = &rest[start + 1..start + end];
+            parts.push(match name {
+                "time" => LogPart::Time,
+                "peer" => LogPart::Peer,
+                "bytes_in" => LogPart::BytesIn,
+                "bytes_out" => LogPart::BytesOut,
+                "status" => LogPart::Status,
+                other => {
+                    return Err(format!(
+                        "Unknown placeholder `{{{}}}`. Available: {{time}}, {{peer}}, {{bytes_in}}, {{bytes_out}}, {{status}}",
+                        other
+                    ))
                 }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    // `WouldBlock` means no client is waiting.
-                    // This is our normal "idle" state. We sleep briefly to avoid
-                    // consuming 100% of the CPU in a tight loop.
-                    thread::sleep(Duration::from_millis(50));
-                    continue; // Go to the next loop iteration to check for Ctrl-C again.
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(LogPart::Literal(rest.to_string()));
+        }
+        Ok(LogFormat(parts))
+    }
+
+    fn render(
+        &self,
+        peer: &Peer,
+        exchange: &Exchange,
+        ok: bool,
+    ) -> String {
+        let mut line = String::new();
+        for part in &self.0 {
+            match part {
+                LogPart::Literal(text) => line.push_str(text),
+                LogPart::Time => line.push_str(
+                    &chrono::Local::now().to_rfc3339_opts(
+                        chrono::SecondsFormat::Secs,
+                        true,
+                    ),
+                ),
+                LogPart::Peer => line.push_str(&peer.to_string()),
+                LogPart::BytesIn => {
+                    line.push_str(&exchange.bytes_in.to_string())
                 }
-                Err(e) => {
-                    // A real error occurred.
-                    eprintln!("Error accepting connection: {}", e);
-                    break;
+                LogPart::BytesOut => {
+                    line.push_str(&exchange.bytes_out.to_string())
+                }
+                LogPart::Status => {
+                    line.push_str(if ok { "ok" } else { "error" })
                 }
             }
         }
+        line
+    }
+}
 
-        Ok(PipelineData::empty())
+/// Where each connection is logged once it is over.
+#[derive(Clone)]
+struct RequestLog {
+    /// A line to stderr (--request-log-format).
+    format: Option<LogFormat>,
+    /// A line of JSON appended to a file (--log), shared by all the
+    /// handler threads.
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl RequestLog {
+    fn record(
+        &self,
+        peer: &Peer,
+        exchange: &Exchange,
+        ok: bool,
+        duration: Duration,
+    ) {
+        if let Some(format) = &self.format {
+            eprintln!("{}", format.render(peer, exchange, ok));
+        }
+        let Some(file) = &self.file else {
+            return;
+        };
+        let entry = serde_json::json!({
+            "time": chrono::Local::now().to_rfc3339_opts(
+                chrono::SecondsFormat::Millis,
+                true,
+            ),
+            "peer": peer.to_string(),
+            "bytes_in": exchange.bytes_in,
+            "bytes_out": exchange.bytes_out,
+            "duration_ms": duration.as_secs_f64() * 1000.0,
+            "status": if ok { "ok" } else { "error" },
+        });
+        // One write per line, under the lock, keeps lines from
+        // different handlers whole.
+        let mut line = entry.to_string();
+        line.push('\n');
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            eprintln!(
+                "Warning: failed to write to the log file: {}",
+                e
+            );
+        }
     }
 }
 
-fn handle_connection(
+/// The room a request is first read into. A larger request grows the
+/// buffer by doubling, up to the size limit.
+const REQUEST_BUFFER: usize = 8192;
+
+/// Reads a request until the client half-closes or sends nothing for the
+/// stream's read timeout (--timeout). Stops once more than `limit` bytes
+/// have arrived, so the caller can detect an oversized request by its
+/// length.
+fn read_request(
+    stream: &mut Connection,
+    limit: usize,
+) -> io::Result<Vec<u8>> {
+    // One byte past the limit is enough to tell an oversized request.
+    let cap = limit.saturating_add(1);
+    let mut request = vec![0u8; REQUEST_BUFFER.min(cap)];
+    let first = loop {
+        match stream.read(&mut request) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            read => break read?,
+        }
+    };
+    request.truncate(first);
+    if first == 0 || first == cap {
+        return Ok(request);
+    }
+
+    // `read_to_end` reads straight into the buffer's spare room, without
+    // zeroing it first, and doubles the buffer when it fills up. What
+    // arrived before the timeout is kept.
+    match Read::take(stream, (cap - first) as u64)
+        .read_to_end(&mut request)
+    {
+        Err(e) if !is_timeout(&e) => Err(e),
+        _ => Ok(request),
+    }
+}
+
+/// Whether a read or write failed because its timeout elapsed. Unix
+/// reports this as `WouldBlock`, Windows as `TimedOut`.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// The error for a failed write to the client, telling a client that
+/// stopped reading apart from other failures.
+fn write_error(e: io::Error, head: Span) -> ShellError {
+    if is_timeout(&e) {
+        return ShellError::GenericError {
+            error: "Timed out writing to socket".into(),
+            msg: "The client stopped reading the response.".into(),
+            span: Some(head),
+            help: Some(
+                "--timeout limits how long a write may stall.".into(),
+            ),
+            inner: vec![],
+        };
+    }
+    ShellError::GenericError {
+        error: "Failed to write to socket".into(),
+        msg: e.to_string(),
+        span: Some(head),
+        help: None,
+        inner: vec![],
+    }
+}
+
+/// What the server does with each request.
+#[derive(Clone)]
+enum Mode {
+    /// Run the closure and send back what it returns.
+    Reply(Closure),
+    /// Pass the request on as an event and send nothing back
+    /// (--observe). The flag is set once the events are dropped.
+    Observe(mpsc::Sender<Value>, Arc<AtomicBool>),
+    /// Send back the same bytes every time (--respond, --respond-file).
+    Fixed(Arc<[u8]>),
+}
+
+/// The per-server state each connection handler thread needs.
+#[derive(Clone)]
+struct Handler {
     engine: EngineInterface,
-    mut stream: TcpStream,
-    closure: Closure,
-    head: nu_protocol::Span,
-) -> Result<(), ShellError> {
+    mode: Mode,
+    until_closure: Option<Closure>,
+    max_request_size: Option<usize>,
+    /// `None` waits for the client indefinitely.
+    read_timeout: Option<Duration>,
+    /// How long a connection may stay open in all.
+    connection_deadline: Option<Duration>,
+    tls_config: Option<Arc<ServerConfig>>,
+    /// Serve several requests per connection.
+    persistent: bool,
+    /// Pass the request to the closure as a stream on `$in`.
+    stream_request: bool,
+    /// The width of the length header framing each request and
+    /// response, if any.
+    length_prefix: Option<usize>,
+    /// Read a PROXY protocol header before anything else.
+    proxy_protocol: bool,
+    /// Taken in the accept loop, or with --proxy-protocol once the
+    /// header names the client.
+    per_ip: Option<PerIpLimit>,
+    head: Span,
+}
+
+impl Handler {
+    /// Whether this is an --observe server whose events were dropped.
+    fn unobserved(&self) -> bool {
+        match &self.mode {
+            Mode::Observe(_, dropped) => dropped.load(Ordering::SeqCst),
+            _ => false,
+        }
+    }
+}
+
+/// Serves a single connection. Returns `false` if a closure
+/// asked the server to stop. With --proxy-protocol, `peer` is replaced by
+/// the client the header names.
+fn handle_connection(
+    handler: &Handler,
+    stream: Connection,
+    peer: &mut Peer,
+    exchange: &mut Exchange,
+) -> Result<bool, ShellError> {
+    let head = handler.head;
+    let deadline = match handler.connection_deadline {
+        Some(limit) => {
+            let close = stream.closer().map_err(|e| {
+                ShellError::GenericError {
+                    error: "Failed to set up --connection-deadline"
+                        .into(),
+                    msg: e.to_string(),
+                    span: Some(head),
+                    help: None,
+                    inner: vec![],
+                }
+            })?;
+            Some(Deadline::start(limit, close))
+        }
+        None => None,
+    };
+    let result = serve_connection(handler, stream, peer, exchange);
+    match deadline {
+        // Whatever failed, it failed because the connection was closed.
+        Some(deadline) if deadline.expired() => {
+            Err(ShellError::GenericError {
+                error: "Connection closed".into(),
+                msg: deadline
+                    .error("--connection-deadline")
+                    .to_string(),
+                span: Some(head),
+                help: None,
+                inner: vec![],
+            })
+        }
+        _ => result,
+    }
+}
+
+fn serve_connection(
+    handler: &Handler,
+    mut stream: Connection,
+    peer: &mut Peer,
+    exchange: &mut Exchange,
+) -> Result<bool, ShellError> {
+    let head = handler.head;
+    // A client that stops reading would otherwise block the handler
+    // forever once the socket's send buffer is full.
     stream
-        .set_read_timeout(Some(Duration::from_secs(10)))
+        .set_write_timeout(handler.read_timeout)
         .map_err(|e| ShellError::GenericError {
-            error: "Failed to set read timeout".into(),
+            error: "Failed to set write timeout".into(),
             msg: e.to_string(),
             span: Some(head),
             help: None,
             inner: vec![],
         })?;
-    let mut request_bytes = vec![0; 4096];
-    let bytes_read = stream.read(&mut request_bytes).map_err(|e| ShellError::GenericError {
-        error: "Failed to read from socket".into(), msg: e.to_string(), span: Some(head),
-        help: Some("This can happen if the client disconnects or the read times out.".into()), inner: vec![]
+    if handler.proxy_protocol {
+        // The header comes first, even before the TLS handshake.
+        stream.set_read_timeout(handler.read_timeout).map_err(|e| {
+            ShellError::GenericError {
+                error: "Failed to set read timeout".into(),
+                msg: e.to_string(),
+                span: Some(head),
+                help: None,
+                inner: vec![],
+            }
+        })?;
+        let source = proxy_protocol::read_header(&mut stream).map_err(
+            |e| ShellError::GenericError {
+                error: "Invalid PROXY protocol header".into(),
+                msg: e.to_string(),
+                span: Some(head),
+                help: Some("With --proxy-protocol, every connection has to come through the load balancer.".into()),
+                inner: vec![],
+            },
+        )?;
+        if let Some(source) = source {
+            *peer = Peer::Ip(source);
+        }
+    }
+    // Held until the connection is done.
+    let _ip_guard = match (&handler.per_ip, peer.ip()) {
+        (Some(limit), Some(ip)) if handler.proxy_protocol => {
+            match limit.acquire(ip) {
+                Some(guard) => Some(guard),
+                None => {
+                    return Err(ShellError::GenericError {
+                        error: "Connection refused".into(),
+                        msg: format!(
+                            "per-IP connection limit reached for {}",
+                            peer
+                        ),
+                        span: Some(head),
+                        help: None,
+                        inner: vec![],
+                    })
+                }
+            }
+        }
+        _ => None,
+    };
+    if let Some(config) = &handler.tls_config {
+        stream = stream.into_tls(config.clone()).map_err(|e| {
+            ShellError::GenericError {
+                error: "Failed to start TLS".into(),
+                msg: e.to_string(),
+                span: Some(head),
+                help: None,
+                inner: vec![],
+            }
+        })?;
+    }
+
+    if handler.stream_request {
+        stream.set_read_timeout(handler.read_timeout).map_err(|e| {
+            ShellError::GenericError {
+                error: "Failed to set read timeout".into(),
+                msg: e.to_string(),
+                span: Some(head),
+                help: None,
+                inner: vec![],
+            }
+        })?;
+        let keep_serving =
+            serve_stream(handler, &mut stream, peer, exchange)?;
+        stream.finish().map_err(|e| write_error(e, head))?;
+        return Ok(keep_serving);
+    }
+
+    stream.set_read_timeout(handler.read_timeout).map_err(|e| {
+        ShellError::GenericError {
+            error: "Failed to set read timeout".into(),
+            msg: e.to_string(),
+            span: Some(head),
+            help: None,
+            inner: vec![],
+        }
     })?;
-    request_bytes.truncate(bytes_read);
+    let mut keep_serving = true;
+    let mut rounds = 0;
+    while keep_serving {
+        let limit = handler.max_request_size.unwrap_or(usize::MAX);
+        let request = match handler.length_prefix {
+            Some(width) => {
+                framing::read_frame(&mut stream, width, limit)
+            }
+            None => read_request(&mut stream, limit).map(Some),
+        };
+        let request_bytes = match request {
+            // A framed client closed the connection between requests.
+            Ok(None) => break,
+            Ok(Some(request)) => request,
+            // An idle client between requests is done with the
+            // connection.
+            Err(e) if rounds > 0 && is_timeout(&e) => break,
+            Err(e) => return Err(ShellError::GenericError {
+                error: "Failed to read from socket".into(), msg: e.to_string(), span: Some(head),
+                help: Some("This can happen if the client disconnects or the read times out.".into()), inner: vec![]
+            }),
+        };
+        if request_bytes.is_empty()
+            && handler.persistent
+            && handler.length_prefix.is_none()
+        {
+            // The client closed the connection.
+            break;
+        }
+        exchange.bytes_in += request_bytes.len();
+        if request_bytes.len() > limit {
+            let _ = stream.write_all(
+                format!(
+                    "Request too large: the limit is {} bytes.\n",
+                    limit
+                )
+                .as_bytes(),
+            );
+            return Err(ShellError::GenericError {
+                error: "Request too large".into(),
+                msg: format!(
+                    "The client sent more than the {} bytes allowed by --max-request-size.",
+                    limit
+                ),
+                span: Some(head),
+                help: None,
+                inner: vec![],
+            });
+        }
 
-    let positional_arg = Value::binary(request_bytes, head);
-    let positional_args = vec![positional_arg];
-    let pipeline_input = None;
-    let spanned_closure = Spanned {
-        item: closure,
-        span: head,
-    };
-    let response_value = engine.eval_closure(
-        &spanned_closure,
-        positional_args,
-        pipeline_input,
-    )?;
+        let local_addr = stream.local_addr();
+        keep_serving = serve_request(
+            handler,
+            request_bytes,
+            peer,
+            local_addr,
+            exchange,
+            |response| {
+                let sent = match handler.length_prefix {
+                    // The length has to be known before it is sent, so
+                    // the response is collected first.
+                    Some(width) => {
+                        let mut payload = Vec::new();
+                        response.read_to_end(&mut payload)?;
+                        framing::write_frame(
+                            &mut stream,
+                            &payload,
+                            width,
+                        )?
+                    }
+                    None => io::copy(response, &mut stream)?,
+                };
+                stream.flush()?;
+                Ok(sent)
+            },
+        )?;
+        rounds += 1;
+        if !handler.persistent {
+            break;
+        }
+    }
 
-    let response_bytes = match response_value {
-        Value::String { val, .. } => val.into_bytes(),
-        Value::Binary { val, .. } => val,
-        other => return Err(ShellError::GenericError {
+    stream.finish().map_err(|e| write_error(e, head))?;
+    Ok(keep_serving)
+}
+
+/// The --observe events as the pipeline reads them. Dropping them, e.g.
+/// when a `first` downstream has what it needs, tells the accept loop to
+/// close the listener right away instead of at the next request.
+struct Observed {
+    events: mpsc::Receiver<Value>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl Iterator for Observed {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        self.events.recv().ok()
+    }
+}
+
+impl Drop for Observed {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Hands a request over to the pipeline for --observe. Returns `false`
+/// once the events are no longer read, which stops the server.
+fn observe(
+    events: &mpsc::Sender<Value>,
+    request: Vec<u8>,
+    peer: &Peer,
+    head: Span,
+) -> bool {
+    let event = Value::record(
+        record! {
+            "from" => Value::string(peer.to_string(), head),
+            "at" => Value::date(chrono::Local::now().fixed_offset(), head),
+            "data" => Value::binary(request, head),
+        },
+        head,
+    );
+    events.send(event).is_ok()
+}
+
+/// Converts the closure's result into the bytes to send back.
+fn response_bytes(
+    value: Value,
+    head: Span,
+) -> Result<Vec<u8>, ShellError> {
+    match value {
+        Value::String { val, .. } => Ok(val.into_bytes()),
+        Value::Binary { val, .. } => Ok(val),
+        other => Err(ShellError::GenericError {
             error: "Unsupported closure output".into(),
-            msg: format!("Expected string or binary from closure, but got {}.", other.get_type()),
+            msg: format!("Expected string, binary or a byte stream from closure, but got {}.", other.get_type()),
             span: Some(head),
-            help: Some("The closure for `socket listen` must return a string or binary value.".into()),
+            help: Some("The closure for `socket listen` must return a string, binary value or byte stream, or {close_server: true} to stop the server.".into()),
             inner: vec![],
         })
-    };
+    }
+}
 
-    stream.write_all(&response_bytes).map_err(|e| {
+/// What the closure returned for a request. A byte stream, e.g. from
+/// `open --raw`, is copied to the client as it is read instead of being
+/// collected in memory first.
+enum Response {
+    Bytes(Vec<u8>),
+    Stream(ByteStream),
+}
+
+impl Response {
+    /// Also returns whether the closure asked to close the server, by
+    /// returning `{close_server: true}`, optionally with a `response` to
+    /// send first.
+    fn from_pipeline(
+        data: PipelineData,
+        head: Span,
+    ) -> Result<(Response, bool), ShellError> {
+        let value = match data {
+            PipelineData::ByteStream(stream, _) => {
+                return Ok((Response::Stream(stream), false))
+            }
+            other => other.into_value(head)?,
+        };
+        let control = match &value {
+            Value::Record { val, .. } => {
+                val.get("close_server").map(|close_server| {
+                    (
+                        close_server.as_bool(),
+                        val.get("response").cloned(),
+                    )
+                })
+            }
+            _ => None,
+        };
+        match control {
+            Some((close_server, response)) => {
+                let bytes = match response {
+                    Some(Value::Nothing { .. }) | None => Vec::new(),
+                    Some(response) => response_bytes(response, head)?,
+                };
+                Ok((Response::Bytes(bytes), close_server?))
+            }
+            None => Ok((
+                Response::Bytes(response_bytes(value, head)?),
+                false,
+            )),
+        }
+    }
+
+    fn into_bytes(self) -> Result<Vec<u8>, ShellError> {
+        match self {
+            Response::Bytes(bytes) => Ok(bytes),
+            Response::Stream(stream) => stream.into_bytes(),
+        }
+    }
+
+    /// Hands the response to `reply` as a reader and returns how many
+    /// bytes it sent.
+    fn send(
+        self,
+        reply: impl FnOnce(&mut dyn Read) -> io::Result<u64>,
+        head: Span,
+    ) -> Result<u64, ShellError> {
+        let sent = match self {
+            Response::Bytes(bytes) => reply(&mut bytes.as_slice()),
+            Response::Stream(stream) => match stream.reader() {
+                Some(mut reader) => reply(&mut reader),
+                None => reply(&mut io::empty()),
+            },
+        };
+        sent.map_err(|e| write_error(e, head))
+    }
+}
+
+/// Runs the closure with the request streamed to it as `$in`, then writes
+/// its response. Returns `false` if the closure asked to close the server.
+fn serve_stream(
+    handler: &Handler,
+    stream: &mut Connection,
+    peer: &Peer,
+    exchange: &mut Exchange,
+) -> Result<bool, ShellError> {
+    let head = handler.head;
+    let reader = stream.try_clone_reader().map_err(|e| {
         ShellError::GenericError {
-            error: "Failed to write to socket".into(),
+            error: "Failed to stream the request".into(),
             msg: e.to_string(),
             span: Some(head),
             help: None,
             inner: vec![],
         }
     })?;
+    let bytes_in = Arc::new(AtomicUsize::new(0));
+    let input = PipelineData::ByteStream(
+        ByteStream::read(
+            UntilIdle {
+                inner: reader,
+                bytes_read: bytes_in.clone(),
+            },
+            head,
+            handler.engine.signals().clone(),
+            ByteStreamType::Binary,
+        ),
+        None,
+    );
+    // --stream is rejected without a closure.
+    let Mode::Reply(closure) = &handler.mode else {
+        return Ok(true);
+    };
+    let meta = peer.to_record(stream.local_addr(), head);
+    let spanned_closure = Spanned {
+        item: closure.clone(),
+        span: head,
+    };
+    let response = handler.engine.eval_closure_with_stream(
+        &spanned_closure,
+        vec![Value::nothing(head), meta],
+        input,
+        true,
+        false,
+    )?;
+    let (response, close_server) =
+        Response::from_pipeline(response, head)?;
+    let sent = response.send(
+        |response| {
+            let sent = io::copy(response, stream)?;
+            stream.flush()?;
+            Ok(sent)
+        },
+        head,
+    )?;
+    exchange.bytes_in += bytes_in.load(Ordering::Relaxed);
+    exchange.bytes_out += sent as usize;
+    Ok(!close_server)
+}
+
+/// Ends a streamed request when the client stays silent for the read
+/// timeout, the same way a buffered request ends. Counts the bytes read
+/// for logging.
+struct UntilIdle<R> {
+    inner: R,
+    bytes_read: Arc<AtomicUsize>,
+}
+
+impl<R: Read> Read for UntilIdle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.bytes_read.fetch_add(n, Ordering::Relaxed);
+                Ok(n)
+            }
+            Err(e) if is_timeout(&e) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
 
-    Ok(())
+/// Answers datagrams on `socket` one at a time until Ctrl+C, `--count`
+/// or a closure ends the server.
+fn serve_udp(
+    socket: &UdpSocket,
+    handler: &Handler,
+    request_log: &RequestLog,
+    count: Option<u64>,
+    shutdown_at: Option<Instant>,
+    stats: &Stats,
+) -> io::Result<()> {
+    // A short timeout lets the loop notice Ctrl+C between datagrams.
+    socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+    let local_addr =
+        socket.local_addr().ok().map(|addr| addr.to_string());
+    let limit = handler.max_request_size.unwrap_or(usize::MAX);
+    let mut buffer = vec![0u8; 65535];
+    let mut answered: u64 = 0;
+    loop {
+        if handler.engine.signals().interrupted() {
+            eprintln!("\nServer shutting down.");
+            return Ok(());
+        }
+        if handler.unobserved() {
+            eprintln!("Server stopped: the --observe output is no longer read.");
+            return Ok(());
+        }
+        if past(shutdown_at) {
+            eprintln!("Server stopped by --shutdown-after.");
+            return Ok(());
+        }
+        let (bytes_read, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e)
+                if is_timeout(&e)
+                    || e.kind() == ErrorKind::Interrupted =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let peer = Peer::Ip(source);
+        let mut exchange = Exchange {
+            bytes_in: bytes_read,
+            bytes_out: 0,
+        };
+        let started = Instant::now();
+        let result = if bytes_read > limit {
+            Err(ShellError::GenericError {
+                error: "Request too large".into(),
+                msg: format!(
+                    "The client sent more than the {} bytes allowed by --max-request-size.",
+                    limit
+                ),
+                span: Some(handler.head),
+                help: None,
+                inner: vec![],
+            })
+        } else {
+            serve_request(
+                handler,
+                buffer[..bytes_read].to_vec(),
+                &peer,
+                local_addr.clone(),
+                &mut exchange,
+                |response| {
+                    // A datagram has to be sent in one piece.
+                    let mut datagram = Vec::new();
+                    response.read_to_end(&mut datagram)?;
+                    // An empty result means there is nothing to send back.
+                    if !datagram.is_empty() {
+                        socket.send_to(&datagram, source)?;
+                    }
+                    Ok(datagram.len() as u64)
+                },
+            )
+        };
+        if let Err(e) = &result {
+            eprintln!("Error in datagram handler: {:?}", e);
+        }
+        stats.record(&exchange);
+        request_log.record(
+            &peer,
+            &exchange,
+            result.is_ok(),
+            started.elapsed(),
+        );
+        if matches!(result, Ok(false)) {
+            eprintln!("Server stopped by a closure.");
+            return Ok(());
+        }
+        answered += 1;
+        if count.is_some_and(|count| answered >= count) {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs the closure for one request and hands its response to `reply`,
+/// which returns how many bytes it sent. Returns `false` if the closure
+/// or the `--until-closure` asked the server to stop.
+fn serve_request(
+    handler: &Handler,
+    request_bytes: Vec<u8>,
+    peer: &Peer,
+    local_addr: Option<String>,
+    exchange: &mut Exchange,
+    reply: impl FnOnce(&mut dyn Read) -> io::Result<u64>,
+) -> Result<bool, ShellError> {
+    let head = handler.head;
+    // Only keep a copy around if the `--until-closure` needs it later.
+    let request_copy = handler
+        .until_closure
+        .as_ref()
+        .map(|_| request_bytes.clone());
+
+    let (response, close_server) = match &handler.mode {
+        Mode::Reply(closure) => {
+            let positional_arg = Value::binary(request_bytes, head);
+            let meta = peer.to_record(local_addr, head);
+            let positional_args = vec![positional_arg, meta];
+            let spanned_closure = Spanned {
+                item: closure.clone(),
+                span: head,
+            };
+            Response::from_pipeline(
+                handler.engine.eval_closure_with_stream(
+                    &spanned_closure,
+                    positional_args,
+                    PipelineData::empty(),
+                    true,
+                    false,
+                )?,
+                head,
+            )?
+        }
+        Mode::Fixed(bytes) => (Response::Bytes(bytes.to_vec()), false),
+        Mode::Observe(events, _) => {
+            return Ok(observe(events, request_bytes, peer, head))
+        }
+    };
+
+    // The `--until-closure` needs the whole response, so a stream is
+    // collected first in that case.
+    let (response, response_copy) = match &handler.until_closure {
+        Some(_) => {
+            let bytes = response.into_bytes()?;
+            (Response::Bytes(bytes.clone()), Some(bytes))
+        }
+        None => (response, None),
+    };
+
+    exchange.bytes_out += response.send(reply, head)? as usize;
+    if close_server {
+        return Ok(false);
+    }
+
+    let (
+        Some(until_closure),
+        Some(request_bytes),
+        Some(response_bytes),
+    ) = (&handler.until_closure, request_copy, response_copy)
+    else {
+        return Ok(true);
+    };
+    let spanned_until = Spanned {
+        item: until_closure.clone(),
+        span: head,
+    };
+    let keep_serving = handler.engine.eval_closure(
+        &spanned_until,
+        vec![
+            Value::binary(request_bytes, head),
+            Value::binary(response_bytes, head),
+        ],
+        None,
+    )?;
+    match keep_serving {
+        Value::Bool { val, .. } => Ok(val),
+        other => Err(ShellError::GenericError {
+            error: "Unsupported --until-closure output".into(),
+            msg: format!(
+                "Expected bool from --until-closure, but got {}.",
+                other.get_type()
+            ),
+            span: Some(head),
+            help: Some(
+                "Return true to keep serving or false to stop the server."
+                    .into(),
+            ),
+            inner: vec![],
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connected pair of loopback TCP streams, the server's end first.
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn read_request_grows_past_the_first_buffer() {
+        let (server, mut client) = tcp_pair();
+        let sent: Vec<u8> =
+            (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        let writer = {
+            let sent = sent.clone();
+            thread::spawn(move || {
+                client.write_all(&sent).unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+            })
+        };
+        let request =
+            read_request(&mut Connection::Tcp(server), 1 << 20)
+                .unwrap();
+        writer.join().unwrap();
+        assert_eq!(request.len(), sent.len());
+        assert!(request == sent);
+    }
+
+    #[test]
+    fn read_request_waits_out_a_pause_shorter_than_the_timeout() {
+        let (server, mut client) = tcp_pair();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let writer = thread::spawn(move || {
+            client.write_all(b"first half, ").unwrap();
+            thread::sleep(Duration::from_millis(800));
+            client.write_all(b"second half").unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+        });
+        let request =
+            read_request(&mut Connection::Tcp(server), 1 << 20)
+                .unwrap();
+        writer.join().unwrap();
+        assert_eq!(request, b"first half, second half");
+    }
+
+    /// Accepts the next client, which may take a moment to show up on a
+    /// non-blocking listener.
+    fn accept_next(listeners: &[Listener]) -> (Connection, Peer) {
+        loop {
+            match accept_any(listeners, 0) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                accepted => return accepted.unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn clients_past_max_connections_wait_for_a_slot() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let listeners = vec![Listener::Tcp(listener)];
+        let clients: Vec<TcpStream> = (0..3)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let limit = ConnectionLimit::new(2);
+
+        // As in accept_loop: a slot first, then the connection.
+        let mut served: Vec<_> = (0..2)
+            .map(|_| {
+                let slot = limit.acquire(Duration::ZERO).unwrap();
+                (slot, accept_next(&listeners))
+            })
+            .collect();
+
+        // The third client stays in the backlog while both are served.
+        assert!(limit.acquire(Duration::from_millis(100)).is_none());
+
+        // It gets in once one of the first two is done.
+        drop(served.pop());
+        let _slot = limit.acquire(Duration::from_millis(100)).unwrap();
+        match accept_next(&listeners).1 {
+            Peer::Ip(peer) => {
+                assert_eq!(peer, clients[2].local_addr().unwrap())
+            }
+            Peer::Unix(_) => panic!("expected a TCP client"),
+        }
+    }
+
+    #[test]
+    fn a_client_that_never_reads_times_out_the_response() {
+        let (server, _client) = tcp_pair();
+        let mut connection = Connection::Tcp(server);
+        connection
+            .set_write_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        // Far more than the socket buffers hold.
+        let response = Response::Bytes(vec![0u8; 64 << 20]);
+        let started = Instant::now();
+        let sent = response.send(
+            |reader| io::copy(reader, &mut connection),
+            Span::test_data(),
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+        match sent {
+            Err(ShellError::GenericError { error, .. }) => {
+                assert_eq!(error, "Timed out writing to socket")
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(n) => panic!("sent {} bytes to a client not reading", n),
+        }
+    }
+
+    #[test]
+    fn per_ip_limit_refuses_extra_connections() {
+        let limit = PerIpLimit::new(1);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let first = limit.acquire(ip).unwrap();
+        assert!(limit.acquire(ip).is_none());
+        assert!(limit.acquire(IpAddr::from([127, 0, 0, 2])).is_some());
+        drop(first);
+        assert!(limit.acquire(ip).is_some());
+    }
+
+    #[test]
+    fn dropping_the_observed_events_is_noticed_at_once() {
+        let (sender, events) = mpsc::channel();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut events = Observed {
+            events,
+            dropped: dropped.clone(),
+        };
+        sender.send(Value::int(1, Span::test_data())).unwrap();
+        assert!(events.next().is_some());
+        assert!(!dropped.load(Ordering::SeqCst));
+        drop(events);
+        // No request has to arrive first for the server to learn of it.
+        assert!(dropped.load(Ordering::SeqCst));
+    }
 }
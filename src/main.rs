@@ -1,20 +1,50 @@
 // Declare the modules that the compiler should look for.
 // It will expect to find `src/connect.rs`, `src/listen.rs`, etc.
+mod config;
 mod connect;
+mod deadline;
+mod encoding;
+mod flags;
+mod forward;
+mod framing;
+mod info;
+mod json;
 mod listen;
+mod ping;
+mod pool;
+mod proxy;
+mod proxy_protocol;
+mod request;
+mod resolve;
+mod scan;
+mod starttls;
+mod throttle;
+mod tls;
 
 // Import the command structs from our modules.
 use crate::connect::Connect;
+use crate::forward::Forward;
+use crate::info::Info;
 use crate::listen::Listen;
+use crate::ping::Ping;
+use crate::pool::Pool;
+use crate::request::Request;
+use crate::resolve::Resolve;
+use crate::scan::Scan;
 
 use nu_plugin::{
     EngineInterface, EvaluatedCall, Plugin, PluginCommand,
 };
 use nu_protocol::{Category, LabeledError, PipelineData, Signature};
+use std::sync::Arc;
 
 // The main struct that represents our plugin to Nushell.
 // It must be public so that child modules can see it.
-pub struct SocketPlugin;
+#[derive(Default)]
+pub struct SocketPlugin {
+    // Idle connections kept for `socket connect --reuse`.
+    pool: Arc<Pool>,
+}
 
 impl Plugin for SocketPlugin {
     fn version(&self) -> String {
@@ -28,7 +58,13 @@ impl Plugin for SocketPlugin {
             Box::new(Socket),
             // The subcommands
             Box::new(Connect),
+            Box::new(Request),
             Box::new(Listen),
+            Box::new(Scan),
+            Box::new(Resolve),
+            Box::new(Ping),
+            Box::new(Forward),
+            Box::new(Info),
         ]
     }
 }
@@ -52,7 +88,7 @@ impl PluginCommand for Socket {
     }
 
     fn extra_description(&self) -> &str {
-        "Run `help socket connect` or `help socket listen` for more information."
+        "Run `help socket connect`, `help socket request`, `help socket listen`, `help socket scan`, `help socket resolve`, `help socket ping`, `help socket forward` or `help socket info` for more information."
     }
 
     // This runs if the user just types `socket` without a subcommand.
@@ -75,7 +111,7 @@ impl PluginCommand for Socket {
 // This starts the plugin and makes it available to Nushell.
 fn main() {
     nu_plugin::serve_plugin(
-        &mut SocketPlugin {},
+        &mut SocketPlugin::default(),
         nu_plugin::MsgPackSerializer {},
     );
 }
@@ -0,0 +1,331 @@
+use super::SocketPlugin;
+use crate::flags::positive_duration;
+use crate::resolve::{host_port, resolve};
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, Category, Example, LabeledError, ListStream, PipelineData,
+    Signals, Signature, Span, Spanned, SyntaxShape, Value,
+};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Ping;
+
+impl PluginCommand for Ping {
+    type Plugin = SocketPlugin;
+
+    fn name(&self) -> &str {
+        "socket ping"
+    }
+
+    fn description(&self) -> &str {
+        "Measure how long it takes to open a TCP connection to a host."
+    }
+
+    fn extra_description(&self) -> &str {
+        "Unlike ICMP ping, this needs no special privileges. A summary is printed to stderr when the pings end, or with --summary returned instead of the attempts. Each attempt tries the host's addresses in turn, like socket connect."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .required(
+                "host",
+                SyntaxShape::String,
+                "The hostname or IP address to ping.",
+            )
+            .required(
+                "port",
+                SyntaxShape::Int,
+                "The TCP port to connect to.",
+            )
+            .named(
+                "count",
+                SyntaxShape::Int,
+                "How many connections to attempt. Defaults to 4; 0 keeps going until Ctrl+C.",
+                Some('c'),
+            )
+            .named(
+                "interval",
+                SyntaxShape::Duration,
+                "How long to wait between attempts. Defaults to 1 second.",
+                Some('i'),
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "How long to wait for each connection. Defaults to 1 second.",
+                Some('t'),
+            )
+            .switch(
+                "summary",
+                "Return a record with the loss and the min/avg/max round-trip time once the pings end, instead of one record per attempt.",
+                Some('s'),
+            )
+            .category(Category::Network)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "socket ping example.com 443",
+                description: "Time four connections to a web server.",
+                result: None,
+            },
+            Example {
+                example: "socket ping 10.0.0.5 22 --count 0 --interval 5sec | where not success",
+                description: "Watch an SSH server and show only the failed attempts.",
+                result: None,
+            },
+            Example {
+                example: "socket ping example.com 443 --count 20 --summary | get loss",
+                description: "Get the share of failed connections, in percent.",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let head = call.head;
+        let host: Spanned<String> = call.req(0)?;
+        let port: Spanned<i64> = call.req(1)?;
+        let port_num = u16::try_from(port.item).map_err(|e| {
+            LabeledError::new("Invalid port number")
+                .with_help(format!(
+                    "Port must be between 0 and 65535. Error: {}",
+                    e
+                ))
+                .with_label("here", port.span)
+        })?;
+
+        let count = match call.get_flag::<Spanned<i64>>("count")? {
+            Some(count) if count.item < 0 => {
+                return Err(LabeledError::new("Invalid count")
+                    .with_help("--count cannot be negative.")
+                    .with_label("here", count.span))
+            }
+            Some(count) if count.item == 0 => None,
+            Some(count) => Some(count.item as u64),
+            None => Some(4),
+        };
+        let interval = positive_duration(call, "interval")?
+            .unwrap_or(Duration::from_secs(1));
+        let timeout = positive_duration(call, "timeout")?
+            .unwrap_or(Duration::from_secs(1));
+
+        let summary = call.has_flag("summary")?;
+
+        let addrs = resolve(&host.item, port_num, None, host.span)?;
+        let mut pinger = Pinger {
+            target: host_port(&host.item, port_num),
+            addrs,
+            count,
+            interval,
+            timeout,
+            signals: engine.signals().clone(),
+            span: head,
+            seq: 0,
+            rtts: Vec::new(),
+            report: !summary,
+        };
+        if summary {
+            // Ctrl+C ends the pings, not the command, so there is a
+            // summary either way.
+            pinger.by_ref().for_each(drop);
+            return Ok(PipelineData::Value(pinger.summary(), None));
+        }
+
+        Ok(PipelineData::ListStream(
+            ListStream::new(pinger, head, engine.signals().clone()),
+            None,
+        ))
+    }
+}
+
+/// Yields one record per connection attempt, and prints a summary when
+/// it is finished or dropped unless the summary is returned instead.
+struct Pinger {
+    /// The host and port, for the summary.
+    target: String,
+    /// The host's addresses, tried in turn on each attempt.
+    addrs: Vec<SocketAddr>,
+    /// `None` pings until interrupted.
+    count: Option<u64>,
+    interval: Duration,
+    timeout: Duration,
+    signals: Signals,
+    span: Span,
+    seq: u64,
+    /// Round-trip times of the successful attempts.
+    rtts: Vec<Duration>,
+    /// Print the summary to stderr when dropped.
+    report: bool,
+}
+
+impl Pinger {
+    /// The time to connect to the first of the host's addresses that
+    /// answers, or `None` if none does. A dead address does not count
+    /// towards the time.
+    fn attempt(&self) -> Option<Duration> {
+        self.addrs.iter().find_map(|addr| {
+            let started = Instant::now();
+            TcpStream::connect_timeout(addr, self.timeout)
+                .ok()
+                .map(|_| started.elapsed())
+        })
+    }
+
+    /// The loss in percent, and the min/avg/max round-trip time if any
+    /// attempt succeeded.
+    fn stats(&self) -> (f64, Option<(Duration, Duration, Duration)>) {
+        let received = self.rtts.len() as u64;
+        let loss = match self.seq {
+            0 => 0.0,
+            seq => (seq - received) as f64 * 100.0 / seq as f64,
+        };
+        let rtt = match (self.rtts.iter().min(), self.rtts.iter().max())
+        {
+            (Some(min), Some(max)) => {
+                let avg = self.rtts.iter().sum::<Duration>()
+                    / received as u32;
+                Some((*min, avg, *max))
+            }
+            _ => None,
+        };
+        (loss, rtt)
+    }
+
+    fn summary(&self) -> Value {
+        let span = self.span;
+        let (loss, rtt) = self.stats();
+        let duration =
+            |pick: fn(&(Duration, Duration, Duration)) -> Duration| {
+                rtt.as_ref().map_or(Value::nothing(span), |rtt| {
+                    Value::duration(nanos(pick(rtt)), span)
+                })
+            };
+        Value::record(
+            record! {
+                "attempted" => Value::int(self.seq as i64, span),
+                "succeeded" => Value::int(self.rtts.len() as i64, span),
+                "loss" => Value::float(loss, span),
+                "min" => duration(|rtt| rtt.0),
+                "avg" => duration(|rtt| rtt.1),
+                "max" => duration(|rtt| rtt.2),
+            },
+            span,
+        )
+    }
+}
+
+impl Iterator for Pinger {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.count.is_some_and(|count| self.seq >= count) {
+            return None;
+        }
+        if self.seq > 0 {
+            // Sleep in short steps so Ctrl+C is noticed quickly.
+            let resume = Instant::now() + self.interval;
+            while Instant::now() < resume {
+                if self.signals.interrupted() {
+                    return None;
+                }
+                thread::sleep(
+                    resume
+                        .saturating_duration_since(Instant::now())
+                        .min(Duration::from_millis(50)),
+                );
+            }
+        }
+        if self.signals.interrupted() {
+            return None;
+        }
+
+        self.seq += 1;
+        let rtt = self.attempt();
+        if let Some(rtt) = rtt {
+            self.rtts.push(rtt);
+        }
+        let span = self.span;
+        Some(Value::record(
+            record! {
+                "seq" => Value::int(self.seq as i64, span),
+                "success" => Value::bool(rtt.is_some(), span),
+                "rtt" => rtt.map_or(Value::nothing(span), |rtt| {
+                    Value::duration(nanos(rtt), span)
+                }),
+            },
+            span,
+        ))
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        if !self.report || self.seq == 0 {
+            return;
+        }
+        let (loss, rtt) = self.stats();
+        eprintln!(
+            "--- {} ping statistics ---\n{} attempted, {} succeeded, {:.1}% loss",
+            self.target,
+            self.seq,
+            self.rtts.len(),
+            loss
+        );
+        if let Some((min, avg, max)) = rtt {
+            eprintln!(
+                "rtt min/avg/max = {:?}/{:?}/{:?}",
+                min, avg, max
+            );
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> i64 {
+    duration.as_nanos().min(i64::MAX as u128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn attempts_fall_back_to_the_next_address() {
+        // A port that was just freed refuses connections.
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pinger = Pinger {
+            target: "localhost".into(),
+            addrs: vec![dead, listener.local_addr().unwrap()],
+            count: Some(2),
+            interval: Duration::from_millis(1),
+            timeout: Duration::from_secs(1),
+            signals: Signals::empty(),
+            span: Span::test_data(),
+            seq: 0,
+            rtts: Vec::new(),
+            report: false,
+        };
+        assert_eq!(pinger.by_ref().count(), 2);
+
+        let summary = pinger.summary();
+        let summary = summary.as_record().unwrap();
+        let field = |name| summary.get(name).unwrap();
+        assert_eq!(field("attempted").as_int().unwrap(), 2);
+        assert_eq!(field("succeeded").as_int().unwrap(), 2);
+        assert_eq!(field("loss").as_float().unwrap(), 0.0);
+        assert!(field("min").as_duration().is_ok());
+    }
+}
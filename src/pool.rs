@@ -0,0 +1,237 @@
+// Connections kept open by `socket connect --reuse`, so a script that
+// talks to the same server over and over pays for the TCP and TLS
+// handshakes only once.
+
+use crate::tls::TlsStream;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How many idle connections are kept per destination.
+const MAX_IDLE: usize = 4;
+
+/// What a pooled connection was opened for. A connection is only handed
+/// to a later call that would have opened the same one.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub host: String,
+    pub port: u16,
+    pub bind: Option<SocketAddr>,
+    pub local_port: Option<u16>,
+    pub proxy_protocol: bool,
+    /// The socket options, which are set when connecting and so are not
+    /// changed for a later call.
+    pub ttl: Option<u32>,
+    pub no_delay: bool,
+    /// The keepalive idle time, probe interval and probe count.
+    pub keepalive: Option<(Duration, Option<Duration>, Option<u32>)>,
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+    /// The TLS settings, or `None` for plain TCP.
+    pub tls: Option<TlsKey>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct TlsKey {
+    pub server_name: String,
+    pub insecure: bool,
+    pub alpn: Vec<String>,
+    pub client_cert: Option<PathBuf>,
+}
+
+/// The idle connections by destination. It is part of the plugin, so the
+/// connections last as long as the plugin process.
+#[derive(Default)]
+pub struct Pool {
+    idle: Mutex<HashMap<PoolKey, Vec<Idle>>>,
+}
+
+/// An idle connection, with what the server sent past the end of the
+/// last reply.
+struct Idle {
+    connection: Connection,
+    pending: Vec<u8>,
+}
+
+impl Pool {
+    /// Takes an idle connection for `key`, dropping any that the server
+    /// closed in the meantime, together with the bytes the server sent
+    /// past the end of its last reply, which come first in the next one.
+    pub fn take(&self, key: &PoolKey) -> Option<(Connection, Vec<u8>)> {
+        let mut idle =
+            self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let connections = idle.get_mut(key)?;
+        while let Some(Idle {
+            connection,
+            pending,
+        }) = connections.pop()
+        {
+            if !pending.is_empty() || connection.is_usable() {
+                return Some((connection, pending));
+            }
+        }
+        None
+    }
+
+    fn put(
+        &self,
+        key: PoolKey,
+        connection: Connection,
+        pending: Vec<u8>,
+    ) {
+        let mut idle =
+            self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let connections = idle.entry(key).or_default();
+        if connections.len() < MAX_IDLE {
+            connections.push(Idle {
+                connection,
+                pending,
+            });
+        }
+    }
+}
+
+/// An open TCP connection, with or without TLS.
+pub enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Connection {
+    /// The underlying TCP connection.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Connection::Tcp(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Whether the connection can take another request: the server has
+    /// not closed it, and has not sent anything unasked that would be
+    /// taken for the start of the next reply.
+    fn is_usable(&self) -> bool {
+        let tcp = self.tcp();
+        if tcp.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let quiet = matches!(
+            tcp.peek(&mut [0u8; 1]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock
+        );
+        tcp.set_nonblocking(false).is_ok() && quiet
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connection in use by one call. It goes back into the pool when
+/// dropped, but only once `complete` is set: a reply that was not read
+/// to its end would otherwise leak into the next one. Bytes read past
+/// the end are put in `leftover` and go back with it.
+pub struct Checkout {
+    /// Only `None` while being dropped.
+    connection: Option<Connection>,
+    /// Left over from the previous reply, read before the connection.
+    pending: Vec<u8>,
+    key: PoolKey,
+    pool: Arc<Pool>,
+    complete: Arc<AtomicBool>,
+    leftover: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Checkout {
+    pub fn new(
+        connection: Connection,
+        pending: Vec<u8>,
+        key: PoolKey,
+        pool: Arc<Pool>,
+        complete: Arc<AtomicBool>,
+        leftover: Arc<Mutex<Vec<u8>>>,
+    ) -> Self {
+        Checkout {
+            connection: Some(connection),
+            pending,
+            key,
+            pool,
+            complete,
+            leftover,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Connection {
+        self.connection
+            .as_ref()
+            .expect("connection taken before drop")
+    }
+
+    pub fn get_mut(&mut self) -> &mut Connection {
+        self.connection
+            .as_mut()
+            .expect("connection taken before drop")
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        if !self.complete.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(connection) = self.connection.take() {
+            let mut leftover =
+                self.leftover.lock().unwrap_or_else(|e| e.into_inner());
+            // Anything still pending was not read, so it comes first.
+            let mut pending = std::mem::take(&mut self.pending);
+            pending.append(&mut leftover);
+            self.pool.put(self.key.clone(), connection, pending);
+        }
+    }
+}
+
+impl Read for Checkout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return self.get_mut().read(buf);
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Checkout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
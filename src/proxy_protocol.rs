@@ -0,0 +1,162 @@
+// The HAProxy PROXY protocol: a header a client or load balancer puts in
+// front of a TCP connection's data to say where the connection really
+// came from.
+
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The twelve bytes that start a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header the protocol allows, CRLF included.
+const V1_MAX: usize = 107;
+
+/// The version 1 (text) header for a connection from `source` to
+/// `destination`, e.g. `PROXY TCP4 192.0.2.1 198.51.100.7 51234 443\r\n`.
+pub fn header_v1(
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> String {
+    let family = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+        // Both ends of a connection have the same family, so this is not
+        // reached; the protocol has a line for it all the same.
+        _ => return "PROXY UNKNOWN\r\n".to_string(),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+}
+
+/// Reads the version 1 or 2 header from the start of a connection,
+/// consuming exactly its bytes, and returns the client address it gives.
+/// `None` means the connection is the load balancer's own, as for a
+/// health check, or the client has no IP address.
+pub fn read_header(
+    stream: &mut impl Read,
+) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start)?;
+    if &start == b"PROXY " {
+        read_v1(stream)
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream)
+    } else {
+        Err(invalid(
+            "The connection does not start with a PROXY protocol header.",
+        ))
+    }
+}
+
+/// Reads the rest of a text header after `PROXY `, a byte at a time:
+/// anything past its end belongs to the request.
+fn read_v1(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut line = b"PROXY ".to_vec();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(invalid(format!(
+                "The PROXY header is longer than the {} bytes allowed.",
+                V1_MAX
+            )));
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line[..line.len() - 2]);
+    let fields: Vec<&str> = line.split(' ').collect();
+    let (family, source, port) = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => return Ok(None),
+        ["PROXY", family, source, _, port, _] => {
+            (*family, *source, *port)
+        }
+        _ => {
+            return Err(invalid(format!(
+                "Malformed PROXY header `{}`.",
+                line
+            )))
+        }
+    };
+    let ip = match (family, source.parse::<IpAddr>()) {
+        ("TCP4", Ok(ip @ IpAddr::V4(_)))
+        | ("TCP6", Ok(ip @ IpAddr::V6(_))) => ip,
+        _ => {
+            return Err(invalid(format!(
+                "`{}` is not a {} source address.",
+                source, family
+            )))
+        }
+    };
+    let port = port.parse::<u16>().map_err(|_| {
+        invalid(format!("`{}` is not a source port.", port))
+    })?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Reads the rest of a binary header after the first six bytes of its
+/// signature. Extensions after the addresses are skipped.
+fn read_v2(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 10];
+    stream.read_exact(&mut fixed)?;
+    if fixed[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid(
+            "The connection does not start with a PROXY protocol header.",
+        ));
+    }
+    let (version, command, family) =
+        (fixed[6] >> 4, fixed[6] & 0x0f, fixed[7] >> 4);
+    let length = u16::from_be_bytes([fixed[8], fixed[9]]);
+    let mut addresses = vec![0u8; length.into()];
+    stream.read_exact(&mut addresses)?;
+    if version != 2 {
+        return Err(invalid(format!(
+            "Unsupported PROXY protocol version {}.",
+            version
+        )));
+    }
+    match command {
+        // LOCAL: the balancer's own connection.
+        0 => return Ok(None),
+        1 => {}
+        _ => {
+            return Err(invalid(format!(
+                "Unknown PROXY protocol command {}.",
+                command
+            )))
+        }
+    }
+    // The source and destination addresses come first, then the two
+    // ports.
+    let source = match (family, addresses.as_slice()) {
+        (1, [a, b, c, d, _, _, _, _, p1, p2, ..]) => SocketAddr::new(
+            Ipv4Addr::new(*a, *b, *c, *d).into(),
+            u16::from_be_bytes([*p1, *p2]),
+        ),
+        (2, addresses) if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            SocketAddr::new(
+                Ipv6Addr::from(ip).into(),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            )
+        }
+        // Unspecified and Unix domain sources have no IP address.
+        (0 | 3, _) => return Ok(None),
+        _ => {
+            return Err(invalid(
+                "The PROXY header has a truncated or unknown address.",
+            ))
+        }
+    };
+    Ok(Some(source))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
@@ -0,0 +1,231 @@
+// `socket request`: `socket connect` for one-shot exchanges, returning the
+// whole reply as a single binary value once the connection is closed.
+
+use super::SocketPlugin;
+use crate::connect::Connect;
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    Category, Example, LabeledError, PipelineData, Signature,
+    SyntaxShape, Value,
+};
+
+pub struct Request;
+
+impl PluginCommand for Request {
+    type Plugin = SocketPlugin;
+
+    fn name(&self) -> &str {
+        "socket request"
+    }
+
+    fn description(&self) -> &str {
+        "Send the input to a remote host and return the complete reply as binary."
+    }
+
+    fn extra_description(&self) -> &str {
+        "Works like `socket connect`, but reads the whole reply into memory and closes the connection before returning, instead of streaming it. The connection is never left open while the rest of the pipeline runs. Use --max-size to bound the memory a reply may take."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .required(
+                "host",
+                SyntaxShape::String,
+                "The hostname or IP address to connect to.",
+            )
+            .optional(
+                "port",
+                SyntaxShape::Int,
+                "The port number to connect to, unless host is given as host:port. Not used with --unix.",
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "Timeout for connecting. Also used for reads unless --read-timeout is given. Defaults to 10 seconds.",
+                Some('t'),
+            )
+            .named(
+                "read-timeout",
+                SyntaxShape::Duration,
+                "Timeout for waiting on data from the remote host. Defaults to --timeout.",
+                None,
+            )
+            .named(
+                "retries",
+                SyntaxShape::Int,
+                "Retry a failed TCP connection this many times. Defaults to 0.",
+                None,
+            )
+            .named(
+                "retry-delay",
+                SyntaxShape::Duration,
+                "How long to wait between connection attempts with --retries. Defaults to 1 second.",
+                None,
+            )
+            .switch("udp", "Use UDP protocol instead of TCP.", Some('u'))
+            .switch(
+                "unix",
+                "Treat host as the path of a Unix domain socket to connect to.",
+                None,
+            )
+            .named(
+                "deadline",
+                SyntaxShape::Duration,
+                "Give up once the whole exchange has taken this long since connecting. Not for UDP.",
+                None,
+            )
+            .named(
+                "max-size",
+                SyntaxShape::Filesize,
+                "Fail once the reply grows past this size.",
+                None,
+            )
+            .switch(
+                "truncate",
+                "With --max-size, end the reply at the limit with a warning instead of failing.",
+                None,
+            )
+            .named(
+                "read-until",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::Binary,
+                    SyntaxShape::String,
+                ]),
+                "End the reply after this delimiter instead of waiting for the server to close the connection. Not for UDP.",
+                None,
+            )
+            .named(
+                "length-prefixed",
+                SyntaxShape::Int,
+                "Send the input and read the reply as one message framed by a big-endian length header of this many bytes: 1, 2, 4 or 8.",
+                None,
+            )
+            .switch(
+                "auto-json",
+                "Send a record, list or table input as a line of JSON. Strings and binary are sent as they are.",
+                None,
+            )
+            .named(
+                "input-encoding",
+                SyntaxShape::String,
+                "Decode string input from hex or base64 before sending it.",
+                None,
+            )
+            .switch(
+                "crlf",
+                "Send line endings in string input as \\r\\n, as text protocols like SMTP and HTTP expect.",
+                None,
+            )
+            .named(
+                "send-file",
+                SyntaxShape::Filepath,
+                "Send the contents of this file instead of the pipeline input.",
+                None,
+            )
+            .switch(
+                "shutdown-write",
+                "Close the sending half of the connection after the input is sent, so the remote host sees end-of-file before it replies.",
+                None,
+            )
+            .switch(
+                "tls",
+                "Encrypt the connection with TLS, verifying the server against the system's trusted certificates.",
+                None,
+            )
+            .switch(
+                "insecure",
+                "With --tls, accept any server certificate. For testing only.",
+                Some('k'),
+            )
+            .named(
+                "tls-sni",
+                SyntaxShape::String,
+                "Server name to send and verify with --tls, if it differs from host.",
+                None,
+            )
+            .named(
+                "client-cert",
+                SyntaxShape::Filepath,
+                "PEM file with a client certificate chain to present with --tls. Requires --client-key.",
+                None,
+            )
+            .named(
+                "client-key",
+                SyntaxShape::Filepath,
+                "PEM file with the private key for --client-cert.",
+                None,
+            )
+            .switch(
+                "ipv4",
+                "Only connect to an IPv4 address of host.",
+                Some('4'),
+            )
+            .switch(
+                "ipv6",
+                "Only connect to an IPv6 address of host.",
+                Some('6'),
+            )
+            .named(
+                "bind",
+                SyntaxShape::String,
+                "Local address to send from, e.g. 192.168.1.5 or 192.168.1.5:0.",
+                Some('b'),
+            )
+            .named(
+                "proxy",
+                SyntaxShape::String,
+                "Connect through a SOCKS5 or HTTP proxy, e.g. socks5://127.0.0.1:1080 or http://proxy:3128.",
+                None,
+            )
+            .switch(
+                "verbose",
+                "Print the resolved addresses, the one connected to and how long each step took to stderr.",
+                Some('v'),
+            )
+            .category(Category::Network)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: r#""GET / HTTP/1.0\r\nHost: example.com\r\n\r\n" | socket request example.com 80 | decode"#,
+                description: "Fetch a page and get the whole response at once.",
+                result: None,
+            },
+            Example {
+                example: r#""il\r\n" | socket request whois.iana.org 43 --max-size 1MB | decode | lines"#,
+                description: "Query a WHOIS server, refusing replies over a megabyte.",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let head = call.head;
+        // Every flag of this command is one of `socket connect`, so the
+        // call can be handed over as it is.
+        match Connect.connect(plugin, engine, call, input, false)? {
+            PipelineData::ByteStream(stream, metadata) => {
+                // Reading to the end drops the stream, which closes the
+                // connection.
+                let reply = stream.into_bytes().map_err(|e| {
+                    LabeledError::new("Failed to read from socket")
+                        .with_help(e.to_string())
+                        .with_label("here", head)
+                })?;
+                Ok(PipelineData::Value(
+                    Value::binary(reply, head),
+                    metadata,
+                ))
+            }
+            // Framed and UDP replies are already read in full.
+            reply => Ok(reply),
+        }
+    }
+}
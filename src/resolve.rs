@@ -0,0 +1,253 @@
+use super::SocketPlugin;
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, Category, Example, LabeledError, PipelineData, Signature,
+    Span, Spanned, SyntaxShape, Value,
+};
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+pub struct Resolve;
+
+impl PluginCommand for Resolve {
+    type Plugin = SocketPlugin;
+
+    fn name(&self) -> &str {
+        "socket resolve"
+    }
+
+    fn description(&self) -> &str {
+        "Look up the IP addresses of a host, as `socket connect` would."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .required(
+                "host",
+                SyntaxShape::String,
+                "The hostname to resolve.",
+            )
+            .optional(
+                "port",
+                SyntaxShape::Int,
+                "A port to include in the results.",
+            )
+            .switch("ipv4", "Only return IPv4 addresses.", Some('4'))
+            .switch("ipv6", "Only return IPv6 addresses.", Some('6'))
+            .category(Category::Network)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "socket resolve example.com",
+                description: "List every address of a host.",
+                result: None,
+            },
+            Example {
+                example: "socket resolve example.com 443 --ipv6",
+                description: "List the IPv6 addresses `socket connect example.com 443 --ipv6` would try.",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let head = call.head;
+        let host: Spanned<String> = call.req(0)?;
+        let port: Option<Spanned<i64>> = call.opt(1)?;
+        let family = Family::from_flags(call)?;
+
+        let port_num = match &port {
+            Some(port) => u16::try_from(port.item).map_err(|e| {
+                LabeledError::new("Invalid port number")
+                    .with_help(format!(
+                        "Port must be between 0 and 65535. Error: {}",
+                        e
+                    ))
+                    .with_label("here", port.span)
+            })?,
+            None => 0,
+        };
+
+        let addrs = resolve(&host.item, port_num, family, host.span)?;
+        let rows = addrs
+            .into_iter()
+            .map(|addr| {
+                let mut row = record! {
+                    "ip" => Value::string(addr.ip().to_string(), head),
+                    "family" => Value::string(
+                        if addr.is_ipv4() { "ipv4" } else { "ipv6" },
+                        head,
+                    ),
+                };
+                if port.is_some() {
+                    row.push(
+                        "port",
+                        Value::int(addr.port().into(), head),
+                    );
+                }
+                Value::record(row, head)
+            })
+            .collect();
+        Ok(PipelineData::Value(Value::list(rows, head), None))
+    }
+}
+
+/// An IP version requested with --ipv4 or --ipv6.
+#[derive(Clone, Copy)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    /// Reads the --ipv4 and --ipv6 switches, which exclude each other.
+    pub fn from_flags(
+        call: &EvaluatedCall,
+    ) -> Result<Option<Family>, LabeledError> {
+        match (call.has_flag("ipv4")?, call.has_flag("ipv6")?) {
+            (true, true) => {
+                Err(LabeledError::new("Invalid flag combination")
+                    .with_help(
+                        "--ipv4 and --ipv6 cannot be used together.",
+                    )
+                    .with_label("here", call.head))
+            }
+            (true, false) => Ok(Some(Family::V4)),
+            (false, true) => Ok(Some(Family::V6)),
+            (false, false) => Ok(None),
+        }
+    }
+
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolves `host` to the addresses `socket connect` tries, in the
+/// resolver's order, keeping only those of `family` if one is given.
+pub fn resolve(
+    host: &str,
+    port: u16,
+    family: Option<Family>,
+    host_span: Span,
+) -> Result<Vec<SocketAddr>, LabeledError> {
+    let host = unbracket(host);
+    // An IP literal needs no lookup. Going through a `host:port` string
+    // would also break IPv6 literals, whose colons run into the port's.
+    let found: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map_err(|e| {
+                LabeledError::new("Failed to resolve host")
+                    .with_help(e.to_string())
+                    .with_label("for this host", host_span)
+            })?
+            .collect(),
+    };
+    let addrs: Vec<SocketAddr> = found
+        .into_iter()
+        .filter(|addr| family.is_none_or(|family| family.matches(addr)))
+        .collect();
+    if addrs.is_empty() {
+        let message = match family {
+            Some(Family::V4) => "No IPv4 addresses found for host",
+            Some(Family::V6) => "No IPv6 addresses found for host",
+            None => "No IP addresses found for host",
+        };
+        return Err(LabeledError::new(message)
+            .with_label("for this host", host_span));
+    }
+    Ok(addrs)
+}
+
+/// Strips the brackets around an IPv6 literal, as in `[::1]`, which is
+/// how such addresses are usually written next to a port.
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|inner| inner.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Joins `host` and `port` into an address string, putting an IPv6
+/// literal in brackets so its colons are not mistaken for the port's.
+pub fn host_port(host: &str, port: impl Display) -> String {
+    match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]:{}", host, port),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+/// Splits a port off `host:port` or `[v6]:port`. Returns `None` for a host
+/// without a port, including a bare IPv6 literal such as `::1`, whose last
+/// group would otherwise pass for a port.
+pub fn split_port(address: &str) -> Option<(&str, &str)> {
+    if address.starts_with('[') {
+        let end = address.find("]:")?;
+        return Some((&address[..=end], &address[end + 2..]));
+    }
+    match address.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Some((host, port)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbracket_strips_only_matching_brackets() {
+        assert_eq!(unbracket("[::1]"), "::1");
+        assert_eq!(
+            unbracket("[2001:db8::8a2e:370:7334]"),
+            "2001:db8::8a2e:370:7334"
+        );
+        assert_eq!(unbracket("::1"), "::1");
+        assert_eq!(unbracket("example.com"), "example.com");
+        assert_eq!(unbracket("[::1"), "[::1");
+    }
+
+    #[test]
+    fn host_port_brackets_ipv6_literals() {
+        assert_eq!(host_port("::1", 80), "[::1]:80");
+        assert_eq!(
+            host_port("2001:db8::8a2e:370:7334", 443),
+            "[2001:db8::8a2e:370:7334]:443"
+        );
+        assert_eq!(host_port("[::1]", 80), "[::1]:80");
+        assert_eq!(host_port("127.0.0.1", 80), "127.0.0.1:80");
+        assert_eq!(
+            host_port("example.com", "http"),
+            "example.com:http"
+        );
+    }
+
+    #[test]
+    fn split_port_leaves_bare_ipv6_literals_whole() {
+        assert_eq!(split_port("::1"), None);
+        assert_eq!(split_port("2001:db8::8a2e:370:7334"), None);
+        assert_eq!(split_port("[::1]:80"), Some(("[::1]", "80")));
+        assert_eq!(split_port("[::1]"), None);
+        assert_eq!(
+            split_port("[2001:db8::1]:8443"),
+            Some(("[2001:db8::1]", "8443"))
+        );
+        assert_eq!(
+            split_port("example.com:80"),
+            Some(("example.com", "80"))
+        );
+        assert_eq!(split_port("example.com"), None);
+    }
+}
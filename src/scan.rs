@@ -0,0 +1,214 @@
+use super::SocketPlugin;
+use crate::flags::positive_duration;
+use crate::resolve::unbracket;
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, Category, Example, LabeledError, ListStream, PipelineData,
+    Signature, Span, Spanned, SyntaxShape, Value,
+};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Scan;
+
+impl PluginCommand for Scan {
+    type Plugin = SocketPlugin;
+
+    fn name(&self) -> &str {
+        "socket scan"
+    }
+
+    fn description(&self) -> &str {
+        "Check which TCP ports on a host accept connections."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .required(
+                "host",
+                SyntaxShape::String,
+                "The hostname or IP address to scan.",
+            )
+            .required(
+                "ports",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::Range,
+                    SyntaxShape::List(Box::new(SyntaxShape::Int)),
+                    SyntaxShape::Int,
+                ]),
+                "The ports to try, as a range, a list or a single port.",
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "How long to wait for each port to answer. Defaults to 1 second.",
+                Some('t'),
+            )
+            .named(
+                "concurrency",
+                SyntaxShape::Int,
+                "How many ports to try at once. Defaults to 1, which reports them in order.",
+                Some('c'),
+            )
+            .category(Category::Network)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "socket scan 127.0.0.1 [22 80 443]",
+                description: "Check a few well-known ports on this machine.",
+                result: None,
+            },
+            Example {
+                example: "socket scan 192.168.1.10 1..1024 --concurrency 64 --timeout 200ms | where open",
+                description: "Find the open low ports on a host, 64 at a time.",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let head = call.head;
+        let host: Spanned<String> = call.req(0)?;
+        let ports_val: Value = call.req(1)?;
+        let ports = parse_ports(ports_val, engine)?;
+
+        let timeout = positive_duration(call, "timeout")?
+            .unwrap_or(Duration::from_secs(1));
+        let concurrency = match call
+            .get_flag::<Spanned<i64>>("concurrency")?
+        {
+            Some(n) if n.item < 1 => {
+                return Err(LabeledError::new("Invalid concurrency")
+                    .with_help("--concurrency must be at least 1.")
+                    .with_label("here", n.span))
+            }
+            Some(n) => n.item as usize,
+            None => 1,
+        };
+
+        let ip = (unbracket(&host.item), 0)
+            .to_socket_addrs()
+            .map_err(|e| {
+                LabeledError::new("Failed to resolve host")
+                    .with_help(e.to_string())
+                    .with_label("for this host", host.span)
+            })?
+            .next()
+            .ok_or_else(|| {
+                LabeledError::new("No IP addresses found for host")
+                    .with_label("for this host", host.span)
+            })?
+            .ip();
+
+        // Workers take ports from a shared queue and report back over a
+        // channel, which the output stream reads from. When the stream is
+        // dropped, sending fails and the workers stop.
+        let workers = concurrency.min(ports.len()).max(1);
+        let queue = Arc::new(Mutex::new(ports.into_iter()));
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let signals = engine.signals().clone();
+            thread::spawn(move || loop {
+                if signals.interrupted() {
+                    break;
+                }
+                let next = queue
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .next();
+                let Some(port) = next else {
+                    break;
+                };
+                let addr = SocketAddr::new(ip, port);
+                let started = Instant::now();
+                let open =
+                    TcpStream::connect_timeout(&addr, timeout).is_ok();
+                let result =
+                    probe_record(port, open, started.elapsed(), head);
+                if tx.send(result).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        Ok(PipelineData::ListStream(
+            ListStream::new(
+                rx.into_iter(),
+                head,
+                engine.signals().clone(),
+            ),
+            None,
+        ))
+    }
+}
+
+fn probe_record(
+    port: u16,
+    open: bool,
+    rtt: Duration,
+    span: Span,
+) -> Value {
+    Value::record(
+        record! {
+            "port" => Value::int(port.into(), span),
+            "open" => Value::bool(open, span),
+            "rtt" => Value::duration(
+                rtt.as_nanos().min(i64::MAX as u128) as i64,
+                span,
+            ),
+        },
+        span,
+    )
+}
+
+/// Turns the `ports` argument into a list of port numbers.
+fn parse_ports(
+    ports: Value,
+    engine: &EngineInterface,
+) -> Result<Vec<u16>, LabeledError> {
+    let span = ports.span();
+    let values = match ports {
+        // There are 65536 ports, so a longer range has a number that is
+        // not one among its first 65537 and fails below, without the
+        // rest of it, possibly endless, being collected.
+        Value::Range { val, .. } => val
+            .into_range_iter(span, engine.signals().clone())
+            .take(usize::from(u16::MAX) + 2)
+            .collect(),
+        Value::List { vals, .. } => vals,
+        other => vec![other],
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let value_span = value.span();
+            let port = value.as_int().map_err(|e| {
+                LabeledError::new("Invalid port")
+                    .with_help(e.to_string())
+                    .with_label("here", value_span)
+            })?;
+            u16::try_from(port).map_err(|_| {
+                LabeledError::new("Invalid port number")
+                    .with_help(format!(
+                        "Port must be between 0 and 65535, got {}.",
+                        port
+                    ))
+                    .with_label("here", value_span)
+            })
+        })
+        .collect()
+}
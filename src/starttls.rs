@@ -0,0 +1,205 @@
+// The plaintext part of `socket connect --starttls`: the exchange that
+// asks a server to switch a connection over to TLS.
+
+use nu_protocol::{LabeledError, Spanned};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+/// Longest line accepted from the server before the upgrade.
+const MAX_LINE: usize = 8192;
+
+/// How to ask the server for TLS.
+pub enum Starttls {
+    /// Read the 220 greeting, send EHLO, then STARTTLS and expect 220.
+    Smtp,
+    /// Read the `* OK` greeting, then send a tagged STARTTLS and expect
+    /// a tagged OK.
+    Imap,
+    /// Send `command` and expect a line starting with `expect`, after
+    /// reading the server's greeting line if `greeting` is set.
+    Custom {
+        greeting: bool,
+        command: String,
+        expect: String,
+    },
+}
+
+impl Starttls {
+    pub fn parse(
+        protocol: &Spanned<String>,
+        command: Option<Spanned<String>>,
+        expect: Option<Spanned<String>>,
+        greeting: bool,
+    ) -> Result<Self, LabeledError> {
+        let starttls = match protocol.item.as_str() {
+            "smtp" => Starttls::Smtp,
+            "imap" => Starttls::Imap,
+            "custom" => {
+                let (Some(command), Some(expect)) = (command, expect)
+                else {
+                    return Err(LabeledError::new("Incomplete STARTTLS setup")
+                        .with_help("--starttls custom needs --starttls-command and --starttls-expect.")
+                        .with_label("here", protocol.span));
+                };
+                return Ok(Starttls::Custom {
+                    greeting,
+                    command: command.item,
+                    expect: expect.item,
+                });
+            }
+            other => {
+                return Err(LabeledError::new(
+                    "Invalid STARTTLS protocol",
+                )
+                .with_help(format!(
+                    "Unknown protocol `{}`. Use smtp, imap or custom.",
+                    other
+                ))
+                .with_label("here", protocol.span))
+            }
+        };
+        if let Some(flag) = command.or(expect) {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help("--starttls-command and --starttls-expect only apply to --starttls custom.")
+                .with_label("here", flag.span));
+        }
+        if greeting {
+            return Err(LabeledError::new("Invalid flag combination")
+                .with_help(
+                    "--starttls-greeting only applies to --starttls custom.",
+                )
+                .with_label("here", protocol.span));
+        }
+        Ok(starttls)
+    }
+
+    /// Runs the exchange on `stream`, leaving it ready for the TLS
+    /// handshake. With `verbose`, the lines sent and received are
+    /// printed to stderr.
+    pub fn upgrade(
+        &self,
+        stream: &TcpStream,
+        verbose: bool,
+    ) -> io::Result<()> {
+        let mut talk = Dialog { stream, verbose };
+        match self {
+            Starttls::Smtp => {
+                talk.smtp_reply("220")?;
+                let local = stream.local_addr()?;
+                talk.send(&format!(
+                    "EHLO {}\r\n",
+                    address_literal(local)
+                ))?;
+                talk.smtp_reply("250")?;
+                talk.send("STARTTLS\r\n")?;
+                talk.smtp_reply("220")?;
+            }
+            Starttls::Imap => {
+                let greeting = talk.line()?;
+                if !greeting.starts_with("* OK") {
+                    return Err(refused(&greeting));
+                }
+                talk.send("a1 STARTTLS\r\n")?;
+                let reply = loop {
+                    let line = talk.line()?;
+                    if !line.starts_with("* ") {
+                        break line;
+                    }
+                };
+                if !reply.starts_with("a1 OK") {
+                    return Err(refused(&reply));
+                }
+            }
+            Starttls::Custom {
+                greeting,
+                command,
+                expect,
+            } => {
+                if *greeting {
+                    talk.line()?;
+                }
+                talk.send(command)?;
+                let reply = talk.line()?;
+                if !reply.starts_with(expect.as_str()) {
+                    return Err(refused(&reply));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Dialog<'a> {
+    stream: &'a TcpStream,
+    verbose: bool,
+}
+
+impl Dialog<'_> {
+    fn send(&mut self, text: &str) -> io::Result<()> {
+        if self.verbose {
+            eprintln!("> {}", text.trim_end());
+        }
+        self.stream.write_all(text.as_bytes())
+    }
+
+    /// Reads one line, a byte at a time: anything read past its end
+    /// would belong to the TLS handshake.
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while line.last() != Some(&b'\n') {
+            if line.len() >= MAX_LINE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "The server sent a line longer than {} bytes.",
+                        MAX_LINE
+                    ),
+                ));
+            }
+            if self.stream.read(&mut byte)? == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "The server closed the connection before TLS was started.",
+                ));
+            }
+            line.push(byte[0]);
+        }
+        let line =
+            String::from_utf8_lossy(&line).trim_end().to_string();
+        if self.verbose {
+            eprintln!("< {}", line);
+        }
+        Ok(line)
+    }
+
+    /// Reads an SMTP reply, which may span several `250-` lines before
+    /// the final `250 ` one, and checks its code.
+    fn smtp_reply(&mut self, code: &str) -> io::Result<()> {
+        loop {
+            let line = self.line()?;
+            if !line.starts_with(code) {
+                return Err(refused(&line));
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn refused(reply: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("The server did not agree to start TLS: {}", reply),
+    )
+}
+
+/// The local address as SMTP writes it in EHLO, e.g. `[192.0.2.1]` or
+/// `[IPv6:2001:db8::1]`.
+fn address_literal(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V4(addr) => format!("[{}]", addr.ip()),
+        SocketAddr::V6(addr) => format!("[IPv6:{}]", addr.ip()),
+    }
+}
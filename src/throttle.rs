@@ -0,0 +1,99 @@
+// Bandwidth limiting for `socket connect --max-rate`: a token bucket per
+// direction, refilled at the allowed number of bytes per second.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wraps a connection so reads and writes each stay under `rate` bytes
+/// per second. Without a rate it passes everything straight through.
+pub struct Throttled<T> {
+    inner: T,
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, rate: Option<u64>) -> Self {
+        Throttled {
+            inner,
+            read: rate.map(Bucket::new),
+            write: rate.map(Bucket::new),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(bucket) = &mut self.read else {
+            return self.inner.read(buf);
+        };
+        let len = bucket.chunk(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        bucket.spend(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(bucket) = &mut self.write else {
+            return self.inner.write(buf);
+        };
+        let len = bucket.chunk(buf.len());
+        let n = self.inner.write(&buf[..len])?;
+        bucket.spend(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Holds at most one second's worth of bytes, so a quiet spell does not
+/// allow a burst above the rate afterwards.
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// How much of a `wanted`-byte transfer to attempt at once. Keeping
+    /// it under the bucket's size bounds each pause to about a second.
+    fn chunk(&self, wanted: usize) -> usize {
+        wanted.min(usize::try_from(self.rate).unwrap_or(usize::MAX))
+    }
+
+    /// Takes `n` bytes' worth of tokens, sleeping off any shortfall.
+    fn spend(&mut self, n: usize) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64()
+            * self.rate as f64;
+        self.tokens = (self.tokens + earned).min(self.rate as f64);
+        self.refilled = now;
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(
+                -self.tokens / self.rate as f64,
+            ));
+        }
+    }
+}